@@ -4,9 +4,10 @@ use std::slice;
 
 use crate::error::Error;
 use crate::histogram::Histogram;
-use crate::image::Image;
-use crate::options::Options;
-use crate::palette::Palette;
+use crate::image::{Image, PixelFormat};
+use crate::octree::Octree;
+use crate::options::{Algorithm, ColorSpace, LinearLight, Options, SplitStrategy};
+use crate::palette::{Color, Palette};
 use crate::quantize::QuantizeResult;
 
 #[repr(C)]
@@ -15,6 +16,150 @@ pub enum QuantizrError {
     QuantizrOk = 0,
     QuantizrValueOutOfRange = 100,
     QuantizrBufferTooSmall = 1,
+    QuantizrQualityTooLow = 99,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum QuantizrPixelFormat {
+    QuantizrPixelFormatRgba8 = 0,
+    QuantizrPixelFormatRgb8 = 1,
+    QuantizrPixelFormatBgra8 = 2,
+    QuantizrPixelFormatArgb8 = 3,
+    QuantizrPixelFormatAbgr8 = 4,
+    QuantizrPixelFormatRgba16 = 5,
+    QuantizrPixelFormatGray8 = 6,
+    QuantizrPixelFormatGrayAlpha8 = 7,
+}
+
+impl std::convert::TryFrom<i32> for QuantizrPixelFormat {
+    type Error = Error;
+
+    fn try_from(format: i32) -> Result<Self, Error> {
+        use QuantizrPixelFormat::*;
+
+        [
+            QuantizrPixelFormatRgba8,
+            QuantizrPixelFormatRgb8,
+            QuantizrPixelFormatBgra8,
+            QuantizrPixelFormatArgb8,
+            QuantizrPixelFormatAbgr8,
+            QuantizrPixelFormatRgba16,
+            QuantizrPixelFormatGray8,
+            QuantizrPixelFormatGrayAlpha8,
+        ]
+        .into_iter()
+        .find(|&f| f as i32 == format)
+        .ok_or(Error::ValueOutOfRange)
+    }
+}
+
+impl std::convert::From<QuantizrPixelFormat> for PixelFormat {
+    fn from(format: QuantizrPixelFormat) -> Self {
+        match format {
+            QuantizrPixelFormat::QuantizrPixelFormatRgba8 => Self::Rgba8,
+            QuantizrPixelFormat::QuantizrPixelFormatRgb8 => Self::Rgb8,
+            QuantizrPixelFormat::QuantizrPixelFormatBgra8 => Self::Bgra8,
+            QuantizrPixelFormat::QuantizrPixelFormatArgb8 => Self::Argb8,
+            QuantizrPixelFormat::QuantizrPixelFormatAbgr8 => Self::Abgr8,
+            QuantizrPixelFormat::QuantizrPixelFormatRgba16 => Self::Rgba16,
+            QuantizrPixelFormat::QuantizrPixelFormatGray8 => Self::Gray8,
+            QuantizrPixelFormat::QuantizrPixelFormatGrayAlpha8 => Self::GrayAlpha8,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum QuantizrLinearLight {
+    QuantizrLinearLightDisabled = 0,
+    QuantizrLinearLightSrgb = 1,
+    QuantizrLinearLightGamma = 2,
+}
+
+impl std::convert::TryFrom<i32> for QuantizrLinearLight {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self, Error> {
+        use QuantizrLinearLight::*;
+
+        [
+            QuantizrLinearLightDisabled,
+            QuantizrLinearLightSrgb,
+            QuantizrLinearLightGamma,
+        ]
+        .into_iter()
+        .find(|&v| v as i32 == value)
+        .ok_or(Error::ValueOutOfRange)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum QuantizrColorSpace {
+    QuantizrColorSpaceRgb = 0,
+    QuantizrColorSpaceOklab = 1,
+}
+
+impl std::convert::TryFrom<i32> for QuantizrColorSpace {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self, Error> {
+        use QuantizrColorSpace::*;
+
+        [QuantizrColorSpaceRgb, QuantizrColorSpaceOklab]
+            .into_iter()
+            .find(|&v| v as i32 == value)
+            .ok_or(Error::ValueOutOfRange)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum QuantizrSplitStrategy {
+    QuantizrSplitStrategyMedianCut = 0,
+    QuantizrSplitStrategyVariance = 1,
+}
+
+impl std::convert::TryFrom<i32> for QuantizrSplitStrategy {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self, Error> {
+        use QuantizrSplitStrategy::*;
+
+        [
+            QuantizrSplitStrategyMedianCut,
+            QuantizrSplitStrategyVariance,
+        ]
+        .into_iter()
+        .find(|&v| v as i32 == value)
+        .ok_or(Error::ValueOutOfRange)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum QuantizrAlgorithm {
+    QuantizrAlgorithmMedianCut = 0,
+    QuantizrAlgorithmWu = 1,
+    QuantizrAlgorithmNeuQuant = 2,
+}
+
+impl std::convert::TryFrom<i32> for QuantizrAlgorithm {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self, Error> {
+        use QuantizrAlgorithm::*;
+
+        [
+            QuantizrAlgorithmMedianCut,
+            QuantizrAlgorithmWu,
+            QuantizrAlgorithmNeuQuant,
+        ]
+        .into_iter()
+        .find(|&v| v as i32 == value)
+        .ok_or(Error::ValueOutOfRange)
+    }
 }
 
 impl std::convert::From<Error> for QuantizrError {
@@ -22,6 +167,7 @@ impl std::convert::From<Error> for QuantizrError {
         match error {
             Error::ValueOutOfRange => Self::QuantizrValueOutOfRange,
             Error::BufferTooSmall => Self::QuantizrBufferTooSmall,
+            Error::QualityTooLow => Self::QuantizrQualityTooLow,
         }
     }
 }
@@ -39,28 +185,268 @@ pub extern "C" fn quantizr_set_max_colors(options: &mut Options, colors: i32) ->
         .map_or(QuantizrError::QuantizrOk, |e| e.into())
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_premultiplied_alpha(
+    options: &mut Options,
+    premultiplied: bool,
+) -> QuantizrError {
+    options.set_premultiplied_alpha(premultiplied);
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_premultiplied_palette(
+    options: &mut Options,
+    premultiplied: bool,
+) -> QuantizrError {
+    options.set_premultiplied_palette(premultiplied);
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_quality(options: &mut Options, min: i32, max: i32) -> QuantizrError {
+    if !(0..=100).contains(&min) || !(0..=100).contains(&max) {
+        return QuantizrError::QuantizrValueOutOfRange;
+    }
+
+    options
+        .set_quality(min as u8, max as u8)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_algorithm(options: &mut Options, algorithm: i32) -> QuantizrError {
+    let Ok(algorithm) = QuantizrAlgorithm::try_from(algorithm) else {
+        return QuantizrError::QuantizrValueOutOfRange;
+    };
+
+    options.set_algorithm(match algorithm {
+        QuantizrAlgorithm::QuantizrAlgorithmMedianCut => Algorithm::MedianCut,
+        QuantizrAlgorithm::QuantizrAlgorithmWu => Algorithm::Wu,
+        QuantizrAlgorithm::QuantizrAlgorithmNeuQuant => Algorithm::NeuQuant,
+    });
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_split_strategy(
+    options: &mut Options,
+    strategy: i32,
+) -> QuantizrError {
+    let Ok(strategy) = QuantizrSplitStrategy::try_from(strategy) else {
+        return QuantizrError::QuantizrValueOutOfRange;
+    };
+
+    options.set_split_strategy(match strategy {
+        QuantizrSplitStrategy::QuantizrSplitStrategyMedianCut => SplitStrategy::MedianCut,
+        QuantizrSplitStrategy::QuantizrSplitStrategyVariance => SplitStrategy::Variance,
+    });
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_speed(options: &mut Options, speed: i32) -> QuantizrError {
+    if !(1..=10).contains(&speed) {
+        return QuantizrError::QuantizrValueOutOfRange;
+    }
+
+    options
+        .set_speed(speed as u8)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_histogram_bits(options: &mut Options, bits: i32) -> QuantizrError {
+    if !(1..=8).contains(&bits) {
+        return QuantizrError::QuantizrValueOutOfRange;
+    }
+
+    options
+        .set_histogram_bits(bits as u8)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_max_histogram_entries(
+    options: &mut Options,
+    max_entries: usize,
+) -> QuantizrError {
+    options
+        .set_max_histogram_entries(max_entries)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_target_error(options: &mut Options, error: f32) -> QuantizrError {
+    options
+        .set_target_error(error)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_add_fixed_color(options: &mut Options, color: Color) -> QuantizrError {
+    options
+        .add_fixed_color(color)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_clear_fixed_colors(options: &mut Options) -> QuantizrError {
+    options.clear_fixed_colors();
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_contrast_maps(
+    options: &mut Options,
+    contrast_maps: bool,
+) -> QuantizrError {
+    options.set_contrast_maps(contrast_maps);
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_linear_light(
+    options: &mut Options,
+    linear_light: i32,
+    gamma: f32,
+) -> QuantizrError {
+    let Ok(linear_light) = QuantizrLinearLight::try_from(linear_light) else {
+        return QuantizrError::QuantizrValueOutOfRange;
+    };
+
+    let linear_light = match linear_light {
+        QuantizrLinearLight::QuantizrLinearLightDisabled => LinearLight::Disabled,
+        QuantizrLinearLight::QuantizrLinearLightSrgb => LinearLight::Srgb,
+        QuantizrLinearLight::QuantizrLinearLightGamma => LinearLight::Gamma(gamma),
+    };
+
+    options
+        .set_linear_light(linear_light)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_color_space(
+    options: &mut Options,
+    color_space: i32,
+) -> QuantizrError {
+    let Ok(color_space) = QuantizrColorSpace::try_from(color_space) else {
+        return QuantizrError::QuantizrValueOutOfRange;
+    };
+
+    options.set_color_space(match color_space {
+        QuantizrColorSpace::QuantizrColorSpaceRgb => ColorSpace::Rgb,
+        QuantizrColorSpace::QuantizrColorSpaceOklab => ColorSpace::Oklab,
+    });
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_set_channel_weights(
+    options: &mut Options,
+    r: f32,
+    g: f32,
+    b: f32,
+    a: f32,
+) -> QuantizrError {
+    options
+        .set_channel_weights([r, g, b, a])
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn quantizr_create_image_rgba<'data>(
     data: *const u8,
     width: i32,
     height: i32,
 ) -> Option<Box<Image<'data>>> {
+    unsafe { create_image(data, width, height, None, PixelFormat::Rgba8) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_create_image_rgb<'data>(
+    data: *const u8,
+    width: i32,
+    height: i32,
+) -> Option<Box<Image<'data>>> {
+    unsafe { create_image(data, width, height, None, PixelFormat::Rgb8) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_create_image<'data>(
+    data: *const u8,
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: i32,
+) -> Option<Box<Image<'data>>> {
+    let format = QuantizrPixelFormat::try_from(format).ok()?;
+
+    unsafe { create_image(data, width, height, Some(stride), format.into()) }
+}
+
+/// Creates an [`Image`] checking the dimensions. Rows are tightly packed if
+/// `stride` is `None`. Returns `None` if any dimension isn't positive or
+/// the image size overflows
+unsafe fn create_image<'data>(
+    data: *const u8,
+    width: i32,
+    height: i32,
+    stride: Option<i32>,
+    format: PixelFormat,
+) -> Option<Box<Image<'data>>> {
+    if width <= 0 || height <= 0 || stride.is_some_and(|s| s <= 0) {
+        return None;
+    }
+
     let uwidth = width as usize;
     let uheight = height as usize;
-    let size: usize = uwidth * uheight * 4;
+    let row_size = uwidth.checked_mul(format.bytes_per_pixel())?;
+    let ustride = stride.map_or(row_size, |s| s as usize);
+
+    let size = ustride.checked_mul(uheight - 1)?.checked_add(row_size)?;
+
+    // Slices can't be larger than isize::MAX bytes
+    if size > isize::MAX as usize {
+        return None;
+    }
 
     let data_slice = unsafe { slice::from_raw_parts(data, size) };
-    Some(
-        Image::new(data_slice, uwidth, uheight)
-            .unwrap_or_else(|e| {
-                // Should never happen.
-                // The only way this can happen is if the buffer is too small for the provided
-                // width and height. But we determine the size from the provided width and height
-                // so this is impossible.
-                panic!("{}", e)
-            })
-            .into(),
-    )
+    Image::with_stride(data_slice, uwidth, uheight, ustride, format)
+        .ok()
+        .map(|image| image.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_image_set_premultiplied(
+    image: &mut Image,
+    premultiplied: bool,
+) -> QuantizrError {
+    image.premultiplied = premultiplied;
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_image_set_importance_map<'data>(
+    image: &mut Image<'data>,
+    map: *const u8,
+    map_size: usize,
+) -> QuantizrError {
+    let map_slice = unsafe { slice::from_raw_parts(map, map_size) };
+
+    image
+        .set_importance_map(map_slice)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
 }
 
 #[unsafe(no_mangle)]
@@ -68,6 +454,74 @@ pub extern "C" fn quantizr_create_histogram() -> Option<Box<Histogram>> {
     Some(Histogram::new().into())
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_histogram_set_bits_per_channel(
+    hist: &mut Histogram,
+    bits: i32,
+) -> QuantizrError {
+    if !(1..=8).contains(&bits) {
+        return QuantizrError::QuantizrValueOutOfRange;
+    }
+
+    hist.set_bits_per_channel(bits as u8)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_histogram_set_max_entries(
+    hist: &mut Histogram,
+    max_entries: usize,
+) -> QuantizrError {
+    hist.set_max_entries(max_entries)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_histogram_add_color(
+    hist: &mut Histogram,
+    color: Color,
+    weight: f64,
+) -> QuantizrError {
+    hist.add_color(color, weight)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_histogram_add_colors(
+    hist: &mut Histogram,
+    colors: *const Color,
+    weights: *const f64,
+    count: usize,
+) -> QuantizrError {
+    if count == 0 {
+        return QuantizrError::QuantizrOk;
+    }
+
+    if colors.is_null() {
+        return QuantizrError::QuantizrValueOutOfRange;
+    }
+
+    let colors = unsafe { slice::from_raw_parts(colors, count) };
+
+    let entries: Vec<(Color, f64)> = if weights.is_null() {
+        colors.iter().map(|&c| (c, 1.0)).collect()
+    } else {
+        let weights = unsafe { slice::from_raw_parts(weights, count) };
+        colors
+            .iter()
+            .copied()
+            .zip(weights.iter().copied())
+            .collect()
+    };
+
+    hist.add_colors(&entries)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_histogram_add_image(
     hist: &mut Histogram,
@@ -77,6 +531,92 @@ pub extern "C" fn quantizr_histogram_add_image(
     QuantizrError::QuantizrOk
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_histogram_add_image_weighted(
+    hist: &mut Histogram,
+    image: &Image,
+    weight: f64,
+    normalize: bool,
+) -> QuantizrError {
+    hist.add_image_weighted(image, weight, normalize)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_histogram_add_image_with_options(
+    hist: &mut Histogram,
+    image: &Image,
+    options: &Options,
+) -> QuantizrError {
+    hist.add_image_with_options(image, options);
+    QuantizrError::QuantizrOk
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_histogram_get_colors_count(hist: &Histogram) -> usize {
+    hist.len()
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_histogram_get_colors(
+    hist: &Histogram,
+    colors: *mut Color,
+    weights: *mut f64,
+    size: usize,
+) -> usize {
+    let entries: Vec<(Color, f64)> = hist.iter().take(size).collect();
+    unsafe { write_colors(&entries, colors, weights) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_histogram_get_dominant_colors(
+    hist: &Histogram,
+    colors: *mut Color,
+    weights: *mut f64,
+    size: usize,
+) -> usize {
+    let entries = hist.dominant_colors(size);
+    unsafe { write_colors(&entries, colors, weights) }
+}
+
+/// Writes colors and weights to the provided buffers. `weights` can be NULL.
+/// Returns the number of written colors, which is 0 if `colors` is NULL
+unsafe fn write_colors(entries: &[(Color, f64)], colors: *mut Color, weights: *mut f64) -> usize {
+    if entries.is_empty() || colors.is_null() {
+        return 0;
+    }
+
+    let colors = unsafe { slice::from_raw_parts_mut(colors, entries.len()) };
+
+    for (c, (color, _)) in colors.iter_mut().zip(entries) {
+        *c = *color;
+    }
+
+    if !weights.is_null() {
+        let weights = unsafe { slice::from_raw_parts_mut(weights, entries.len()) };
+
+        for (w, (_, weight)) in weights.iter_mut().zip(entries) {
+            *w = *weight;
+        }
+    }
+
+    entries.len()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_create_octree(max_nodes: usize) -> Option<Box<Octree>> {
+    Octree::with_max_nodes(max_nodes)
+        .ok()
+        .map(|octree| octree.into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_octree_add_image(octree: &mut Octree, image: &Image) -> QuantizrError {
+    octree.add_image(image);
+    QuantizrError::QuantizrOk
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_quantize(
     image: &Image,
@@ -85,6 +625,24 @@ pub extern "C" fn quantizr_quantize(
     Some(QuantizeResult::quantize(image, options).into())
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_try_quantize(
+    image: &Image,
+    options: &Options,
+    result: &mut Option<Box<QuantizeResult>>,
+) -> QuantizrError {
+    match QuantizeResult::try_quantize(image, options) {
+        Ok(res) => {
+            *result = Some(res.into());
+            QuantizrError::QuantizrOk
+        }
+        Err(e) => {
+            *result = None;
+            e.into()
+        }
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_quantize_histogram(
     hist: &Histogram,
@@ -93,6 +651,64 @@ pub extern "C" fn quantizr_quantize_histogram(
     Some(QuantizeResult::quantize_histogram(hist, options).into())
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_quantize_octree(
+    octree: &Octree,
+    options: &Options,
+) -> Option<Box<QuantizeResult>> {
+    Some(QuantizeResult::quantize_octree(octree, options).into())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_try_quantize_histogram(
+    hist: &Histogram,
+    options: &Options,
+    result: &mut Option<Box<QuantizeResult>>,
+) -> QuantizrError {
+    match QuantizeResult::try_quantize_histogram(hist, options) {
+        Ok(res) => {
+            *result = Some(res.into());
+            QuantizrError::QuantizrOk
+        }
+        Err(e) => {
+            *result = None;
+            e.into()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_quantize_spatial(
+    image: &Image,
+    options: &Options,
+    buffer: *mut u8,
+    buffer_size: usize,
+    result: &mut Option<Box<QuantizeResult>>,
+) -> QuantizrError {
+    let buf = unsafe { slice::from_raw_parts_mut(buffer, buffer_size) };
+
+    match QuantizeResult::quantize_spatial(image, options, buf) {
+        Ok(res) => {
+            *result = Some(res.into());
+            QuantizrError::QuantizrOk
+        }
+        Err(e) => {
+            *result = None;
+            e.into()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_create_result_from_palette(
+    palette: &Palette,
+    options: &Options,
+) -> Option<Box<QuantizeResult>> {
+    QuantizeResult::from_palette(palette, options)
+        .ok()
+        .map(|result| result.into())
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_set_dithering_level(
     result: &mut QuantizeResult,
@@ -109,11 +725,40 @@ pub extern "C" fn quantizr_get_palette(result: &QuantizeResult) -> Option<&Palet
     Some(result.get_palette())
 }
 
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_write_palette(
+    palette: &Palette,
+    format: i32,
+    buffer: *mut u8,
+    buffer_size: usize,
+) -> QuantizrError {
+    let Ok(format) = QuantizrPixelFormat::try_from(format) else {
+        return QuantizrError::QuantizrValueOutOfRange;
+    };
+
+    let buf = unsafe { slice::from_raw_parts_mut(buffer, buffer_size) };
+
+    palette
+        .write_to(format.into(), buf)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_get_error(result: &QuantizeResult) -> f32 {
     result.get_error()
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_get_colors_count(result: &QuantizeResult) -> u32 {
+    result.get_colors_count()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_get_quality(result: &QuantizeResult) -> i32 {
+    result.get_quality() as i32
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn quantizr_remap(
     result: &QuantizeResult,
@@ -129,6 +774,22 @@ pub unsafe extern "C" fn quantizr_remap(
         .map_or(QuantizrError::QuantizrOk, |e| e.into())
 }
 
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quantizr_remap_with_stride(
+    result: &QuantizeResult,
+    image: &Image,
+    buffer: *mut u8,
+    buffer_size: usize,
+    stride: usize,
+) -> QuantizrError {
+    let buf = unsafe { slice::from_raw_parts_mut(buffer, buffer_size) };
+
+    result
+        .remap_image_with_stride(image, buf, stride)
+        .err()
+        .map_or(QuantizrError::QuantizrOk, |e| e.into())
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_free_result(result: Box<QuantizeResult>) {
     std::mem::drop(result)
@@ -139,6 +800,11 @@ pub extern "C" fn quantizr_free_histogram(hist: Box<Histogram>) {
     std::mem::drop(hist)
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn quantizr_free_octree(octree: Box<Octree>) {
+    std::mem::drop(octree)
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn quantizr_free_image(image: Box<Image>) {
     std::mem::drop(image)
@@ -148,3 +814,157 @@ pub extern "C" fn quantizr_free_image(image: Box<Image>) {
 pub extern "C" fn quantizr_free_options(options: Box<Options>) {
     std::mem::drop(options)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capi_create_image_invalid_dimensions() {
+        let data = [0u8; 16];
+        let rgba = QuantizrPixelFormat::QuantizrPixelFormatRgba8 as i32;
+
+        unsafe {
+            assert!(quantizr_create_image(data.as_ptr(), 2, 2, 8, rgba).is_some());
+            assert!(quantizr_create_image(data.as_ptr(), 2, 2, -8, rgba).is_none());
+            assert!(quantizr_create_image(data.as_ptr(), -2, 2, 8, rgba).is_none());
+            assert!(quantizr_create_image(data.as_ptr(), 2, 0, 8, rgba).is_none());
+            assert!(
+                quantizr_create_image(data.as_ptr(), i32::MAX, i32::MAX, i32::MAX, rgba).is_none()
+            );
+            assert!(quantizr_create_image_rgba(data.as_ptr(), -1, 2).is_none());
+        }
+    }
+
+    #[test]
+    fn test_capi_invalid_pixel_format() {
+        let data = [0u8; 16];
+        let palette = Palette {
+            count: 1,
+            entries: [Color::default(); 256],
+        };
+        let mut buf = [0u8; 4];
+
+        unsafe {
+            assert!(quantizr_create_image(data.as_ptr(), 2, 2, 8, 8).is_none());
+            assert!(quantizr_create_image(data.as_ptr(), 2, 2, 8, -1).is_none());
+
+            assert!(matches!(
+                quantizr_write_palette(&palette, 42, buf.as_mut_ptr(), buf.len()),
+                QuantizrError::QuantizrValueOutOfRange
+            ));
+            assert!(matches!(
+                quantizr_write_palette(&palette, 0, buf.as_mut_ptr(), buf.len()),
+                QuantizrError::QuantizrOk
+            ));
+        }
+    }
+
+    #[test]
+    fn test_capi_invalid_linear_light() {
+        let mut opts = Options::default();
+
+        assert!(matches!(
+            quantizr_set_linear_light(&mut opts, 3, 2.2),
+            QuantizrError::QuantizrValueOutOfRange
+        ));
+        assert!(opts.get_linear_light() == LinearLight::Disabled);
+
+        assert!(matches!(
+            quantizr_set_linear_light(
+                &mut opts,
+                QuantizrLinearLight::QuantizrLinearLightGamma as i32,
+                2.2
+            ),
+            QuantizrError::QuantizrOk
+        ));
+        assert!(opts.get_linear_light() == LinearLight::Gamma(2.2));
+    }
+
+    #[test]
+    fn test_capi_invalid_color_space() {
+        let mut opts = Options::default();
+
+        assert!(matches!(
+            quantizr_set_color_space(&mut opts, 2),
+            QuantizrError::QuantizrValueOutOfRange
+        ));
+        assert!(opts.get_color_space() == ColorSpace::Rgb);
+
+        assert!(matches!(
+            quantizr_set_color_space(
+                &mut opts,
+                QuantizrColorSpace::QuantizrColorSpaceOklab as i32
+            ),
+            QuantizrError::QuantizrOk
+        ));
+        assert!(opts.get_color_space() == ColorSpace::Oklab);
+    }
+
+    #[test]
+    fn test_capi_invalid_split_strategy() {
+        let mut opts = Options::default();
+
+        assert!(matches!(
+            quantizr_set_split_strategy(&mut opts, -1),
+            QuantizrError::QuantizrValueOutOfRange
+        ));
+        assert!(opts.get_split_strategy() == SplitStrategy::MedianCut);
+
+        assert!(matches!(
+            quantizr_set_split_strategy(
+                &mut opts,
+                QuantizrSplitStrategy::QuantizrSplitStrategyVariance as i32
+            ),
+            QuantizrError::QuantizrOk
+        ));
+        assert!(opts.get_split_strategy() == SplitStrategy::Variance);
+    }
+
+    #[test]
+    fn test_capi_invalid_algorithm() {
+        let mut opts = Options::default();
+
+        assert!(matches!(
+            quantizr_set_algorithm(&mut opts, 3),
+            QuantizrError::QuantizrValueOutOfRange
+        ));
+        assert!(opts.get_algorithm() == Algorithm::MedianCut);
+
+        assert!(matches!(
+            quantizr_set_algorithm(
+                &mut opts,
+                QuantizrAlgorithm::QuantizrAlgorithmNeuQuant as i32
+            ),
+            QuantizrError::QuantizrOk
+        ));
+        assert!(opts.get_algorithm() == Algorithm::NeuQuant);
+    }
+
+    #[test]
+    fn test_capi_histogram_colors_null() {
+        let mut hist = Histogram::new();
+
+        unsafe {
+            assert!(matches!(
+                quantizr_histogram_add_colors(&mut hist, std::ptr::null(), std::ptr::null(), 0),
+                QuantizrError::QuantizrOk
+            ));
+            assert!(matches!(
+                quantizr_histogram_add_colors(&mut hist, std::ptr::null(), std::ptr::null(), 2),
+                QuantizrError::QuantizrValueOutOfRange
+            ));
+
+            let colors = [Color::default()];
+            quantizr_histogram_add_colors(&mut hist, colors.as_ptr(), std::ptr::null(), 1);
+
+            let (colors, weights) = (std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(quantizr_histogram_get_colors(&hist, colors, weights, 0), 0);
+            assert_eq!(quantizr_histogram_get_colors(&hist, colors, weights, 1), 0);
+            assert_eq!(
+                quantizr_histogram_get_dominant_colors(&hist, colors, weights, 1),
+                0
+            );
+        }
+    }
+}
@@ -1,22 +1,27 @@
 use crate::ord_float::OrdFloat32;
 
-use crate::histogram::{Histogram, HistogramEntry};
+use crate::colorspace::ColorEntry;
+use crate::moments::norm_sq;
+use crate::options::SplitStrategy;
 
 pub(crate) struct Cluster<'clust> {
-    pub entries: Vec<&'clust HistogramEntry>,
+    pub entries: Vec<&'clust ColorEntry>,
     pub mean: [f32; 4],
     pub weight: f32,
     pub chan_diff: f32,
+    /// Weighted sum of squared distances between the entries and the mean
+    pub error: f32,
     widest_chan: u8,
 }
 
 impl<'clust> Cluster<'clust> {
-    pub(crate) fn new(entries: Vec<&'clust HistogramEntry>) -> Self {
+    pub(crate) fn new(entries: Vec<&'clust ColorEntry>) -> Self {
         let mut cluster = Self {
             entries,
             mean: [0.0; 4],
             weight: 0.0,
             chan_diff: 0.0,
+            error: 0.0,
             widest_chan: 0,
         };
 
@@ -25,39 +30,24 @@ impl<'clust> Cluster<'clust> {
         cluster
     }
 
-    pub(crate) fn from_histogram(hist: &'clust Histogram) -> Self {
-        let mut entries = Vec::with_capacity(hist.map.len());
-
-        for entry in hist.map.values() {
-            entries.push(entry)
-        }
-
-        Self::new(entries)
+    pub(crate) fn from_entries(entries: &'clust [ColorEntry]) -> Self {
+        Self::new(entries.iter().collect())
     }
 
     fn calc_stats(&mut self) {
         self.mean = [0.0; 4];
         self.weight = 0.0;
+        self.error = 0.0;
 
         if self.entries.is_empty() {
             self.chan_diff = 0.0;
             return;
         }
 
-        let mut tmp;
-
         for e in self.entries.iter() {
-            let weight = e.weight as f32;
+            add_color(&mut self.mean, &e.color, e.weight);
 
-            tmp = [
-                e.color[0] as f32,
-                e.color[1] as f32,
-                e.color[2] as f32,
-                e.color[3] as f32,
-            ];
-            add_color(&mut self.mean, &tmp, weight);
-
-            self.weight += weight;
+            self.weight += e.weight;
         }
 
         self.mean[0] /= self.weight;
@@ -68,15 +58,15 @@ impl<'clust> Cluster<'clust> {
         let mut diff_sum: [f32; 4] = [0f32; 4];
 
         for &e in self.entries.iter() {
-            let weight = e.weight as f32;
-
-            tmp = [
-                e.color[0] as f32,
-                e.color[1] as f32,
-                e.color[2] as f32,
-                e.color[3] as f32,
-            ];
-            add_diff(&mut diff_sum, &tmp, &self.mean, weight);
+            add_diff(&mut diff_sum, &e.color, &self.mean, e.weight);
+
+            let dist: f32 = e
+                .color
+                .iter()
+                .zip(self.mean)
+                .map(|(c, m)| (c - m) * (c - m))
+                .sum();
+            self.error += dist * e.weight;
         }
 
         let (chan, max_diff_sum) = diff_sum
@@ -89,14 +79,28 @@ impl<'clust> Cluster<'clust> {
         self.widest_chan = chan as u8;
     }
 
-    pub(crate) fn split_into(self, max_colors: usize) -> Vec<Self> {
+    /// Splits the cluster into at most `max_colors` clusters.
+    /// If `target_error` is greater than zero, splitting stops once
+    /// the estimated mean squared error drops to it
+    pub(crate) fn split_into(
+        self,
+        max_colors: usize,
+        target_error: f32,
+        strategy: SplitStrategy,
+    ) -> Vec<Self> {
         let max_colors_f32 = max_colors as f32;
+        let total_weight = self.weight;
+        let mut total_error = self.error;
 
         let mut clusters = Vec::<Self>::with_capacity(max_colors);
 
         clusters.push(self);
 
         while clusters.len() < max_colors {
+            if target_error > 0.0 && total_error <= target_error * total_weight {
+                break;
+            }
+
             // We want to split bigger clusters in the beginning,
             // and clusters with bigger chan_diff in the end
             let weight_ratio = 0.75 - (clusters.len() as f32 + 1.0) / max_colors_f32 / 2.0;
@@ -107,7 +111,10 @@ impl<'clust> Cluster<'clust> {
                 .enumerate()
                 .filter(|(_, c)| c.chan_diff > 0.0)
                 .map(|(i, c)| {
-                    let priority = c.chan_diff * c.weight.powf(weight_ratio);
+                    let priority = match strategy {
+                        SplitStrategy::MedianCut => c.chan_diff * c.weight.powf(weight_ratio),
+                        SplitStrategy::Variance => c.error,
+                    };
                     (i, priority)
                 })
                 .max_by_key(|&(_, p)| OrdFloat32::from(p))
@@ -119,7 +126,12 @@ impl<'clust> Cluster<'clust> {
                 None => break,
             };
 
-            let (mut c1, mut c2) = to_split.split();
+            let (mut c1, mut c2) = match strategy {
+                SplitStrategy::MedianCut => to_split.split(),
+                SplitStrategy::Variance => to_split.split_variance(),
+            };
+
+            total_error += c1.error + c2.error - to_split.error;
 
             if c1.entries.is_empty() {
                 c2.chan_diff = 0.0;
@@ -148,22 +160,22 @@ impl<'clust> Cluster<'clust> {
         let mut lt: usize = 0;
         let mut gt: usize = self.entries.len() - 1;
 
-        let mut lt_weight: usize = 0;
-        let mut gt_weight: usize = 0;
+        let mut lt_weight: f32 = 0.0;
+        let mut gt_weight: f32 = 0.0;
 
         while i <= gt {
             let entry = self.entries[i];
-            let val = entry.color[widest_chan] as f32;
+            let val = entry.color[widest_chan];
 
             if val < widest_chan_mean {
-                lt_weight += entry.weight as usize;
+                lt_weight += entry.weight;
                 if lt != i {
                     self.entries.swap(lt, i);
                 }
                 lt += 1;
                 i += 1;
             } else if val > widest_chan_mean {
-                gt_weight += entry.weight as usize;
+                gt_weight += entry.weight;
                 self.entries.swap(gt, i);
                 gt -= 1;
             } else {
@@ -180,6 +192,74 @@ impl<'clust> Cluster<'clust> {
 
         (Self::new(sp1.to_vec()), Self::new(sp2.to_vec()))
     }
+
+    /// Splits the cluster along the channel and at the point that minimize
+    /// the total weighted variance of the resulting clusters
+    fn split_variance(&mut self) -> (Self, Self) {
+        let len = self.entries.len();
+
+        let mut total_color = [0f64; 4];
+        let mut total_weight = 0f64;
+
+        for e in self.entries.iter() {
+            for (tc, c) in total_color.iter_mut().zip(e.color) {
+                *tc += c as f64 * e.weight as f64;
+            }
+            total_weight += e.weight as f64;
+        }
+
+        // Splitting reduces the variance by the most when the sum of
+        // |sum of colors|^2 / weight of the halves is the largest
+        let mut best: Option<(f64, usize, usize)> = None;
+
+        for chan in 0..4 {
+            self.entries
+                .sort_unstable_by_key(|e| OrdFloat32::from(e.color[chan]));
+
+            let mut color = [0f64; 4];
+            let mut weight = 0f64;
+
+            for i in 0..len - 1 {
+                let e = self.entries[i];
+
+                for (sc, c) in color.iter_mut().zip(e.color) {
+                    *sc += c as f64 * e.weight as f64;
+                }
+                weight += e.weight as f64;
+
+                if self.entries[i + 1].color[chan] == e.color[chan] {
+                    continue;
+                }
+
+                let rest_weight = total_weight - weight;
+                if weight <= 0.0 || rest_weight <= 0.0 {
+                    continue;
+                }
+
+                let mut rest_color = total_color;
+                for (rc, c) in rest_color.iter_mut().zip(color) {
+                    *rc -= c;
+                }
+
+                let gain = norm_sq(&color) / weight + norm_sq(&rest_color) / rest_weight;
+
+                if best.is_none_or(|(g, _, _)| gain > g) {
+                    best = Some((gain, chan, i + 1));
+                }
+            }
+        }
+
+        let Some((_, chan, split_pos)) = best else {
+            return (Self::new(self.entries.clone()), Self::new(Vec::new()));
+        };
+
+        self.entries
+            .sort_unstable_by_key(|e| OrdFloat32::from(e.color[chan]));
+
+        let (sp1, sp2) = self.entries.split_at(split_pos);
+
+        (Self::new(sp1.to_vec()), Self::new(sp2.to_vec()))
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -284,3 +364,81 @@ fn add_diff(dst: &mut [f32; 4], a: &[f32; 4], b: &[f32; 4], weight: f32) {
     dst[2] += (a[2] - b[2]).abs() * weight;
     dst[3] += (a[3] - b[3]).abs() * weight;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(values: &[[f32; 4]]) -> Vec<ColorEntry> {
+        values
+            .iter()
+            .map(|&color| ColorEntry { color, weight: 1.0 })
+            .collect()
+    }
+
+    #[test]
+    fn test_cluster_split_variance() {
+        let entries = entries(&[
+            [0.0, 0.0, 0.0, 255.0],
+            [100.0, 10.0, 0.0, 255.0],
+            [110.0, 0.0, 0.0, 255.0],
+            [120.0, 5.0, 0.0, 255.0],
+            [255.0, 0.0, 0.0, 255.0],
+        ]);
+
+        let (c1, c2) = Cluster::from_entries(&entries).split_variance();
+        let error = c1.error + c2.error;
+
+        // The best cut found by trying every channel and every position
+        let mut best = f32::INFINITY;
+        for chan in 0..4 {
+            for e in entries.iter() {
+                let threshold = e.color[chan];
+                let (lo, hi): (Vec<_>, Vec<_>) =
+                    entries.iter().partition(|e| e.color[chan] < threshold);
+
+                if lo.is_empty() || hi.is_empty() {
+                    continue;
+                }
+
+                best = best.min(Cluster::new(lo).error + Cluster::new(hi).error);
+            }
+        }
+
+        assert!((error - best).abs() <= best * 0.0001);
+
+        // Cutting at the mean leaves the outlier with its neighbours
+        let (m1, m2) = Cluster::from_entries(&entries).split();
+        assert!(error < m1.error + m2.error);
+    }
+
+    #[test]
+    fn test_cluster_split_into_variance() {
+        // Three tight groups of different sizes
+        let mut values = Vec::new();
+        for (center, count) in [(20.0, 50), (128.0, 5), (230.0, 20)] {
+            for i in 0..count {
+                values.push([center + (i % 5) as f32, 64.0, 64.0, 255.0]);
+            }
+        }
+        let entries = entries(&values);
+
+        let clusters = Cluster::from_entries(&entries).split_into(3, 0.0, SplitStrategy::Variance);
+
+        assert_eq!(clusters.len(), 3);
+        for c in clusters.iter() {
+            let (min, max) = c.entries.iter().fold((f32::MAX, f32::MIN), |(lo, hi), e| {
+                (lo.min(e.color[0]), hi.max(e.color[0]))
+            });
+            assert!(max - min < 5.0);
+        }
+
+        // Splitting stops once the error meets the target
+        let clusters =
+            Cluster::from_entries(&entries).split_into(16, 10.0, SplitStrategy::Variance);
+        let total_error: f32 = clusters.iter().map(|c| c.error).sum();
+
+        assert!(clusters.len() < 16);
+        assert!(total_error <= 10.0 * entries.len() as f32);
+    }
+}
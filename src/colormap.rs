@@ -1,27 +1,71 @@
-use crate::ord_float::OrdFloat32;
-
 use crate::vpsearch;
 
 use crate::cluster::Cluster;
-use crate::histogram::Histogram;
-use crate::palette::Palette;
+use crate::colorspace::{ColorEntry, WorkingSpace};
+use crate::ord_float::OrdFloat32;
+use crate::palette::{Color, Palette};
+
+/// Palette refinement effort that depends on the speed
+struct Refinement {
+    /// Maximum number of k-means passes
+    max_passes: usize,
+    /// K-means stops if a pass improves the error by less than this fraction
+    convergence: f32,
+    /// Whether unused palette entries should be moved to the worst colors
+    reseed: bool,
+}
+
+impl Refinement {
+    fn new(speed: u8) -> Self {
+        let (max_passes, convergence, reseed) = match speed {
+            1 => (16, 0.0001, true),
+            2 => (8, 0.0005, true),
+            3 => (4, 0.001, false),
+            4..=7 => (2, 0.0, false),
+            _ => (1, 0.0, false),
+        };
+
+        Self {
+            max_passes,
+            convergence,
+            reseed,
+        }
+    }
+}
 
 pub(crate) struct Colormap {
     palette: Palette,
     tree: vpsearch::SearchTree,
+    /// Mean squared error of the pixels
     pub(crate) error: f32,
+    /// Sum of squared errors of the colors regardless of their weights.
+    /// It's used to estimate the dithering threshold
+    pub(crate) sq_error_sum: f32,
 }
 
 impl Colormap {
-    pub(crate) fn from_clusters(clusters: &Vec<Cluster>) -> Self {
-        assert!(clusters.len() <= 256);
+    pub(crate) fn from_clusters(
+        clusters: &Vec<Cluster>,
+        fixed: &[Color],
+        space: &WorkingSpace,
+        speed: u8,
+    ) -> Self {
+        let refinement = Refinement::new(speed);
 
-        let size = clusters.len();
+        assert!(clusters.len() + fixed.len() <= 256);
+
+        let size = fixed.len() + clusters.len();
         let mut entries = [[0f32; 4]; 256];
         let mut weights = [0f32; 256];
         let mut total_weight = 0f32;
 
+        for (e, c) in entries.iter_mut().zip(fixed) {
+            *e = space.rgba_to_working(space.palette_to_rgba(c));
+        }
+
         clusters.iter().enumerate().for_each(|(i, c)| {
+            let i = i + fixed.len();
+
             entries[i] = [c.mean[0], c.mean[1], c.mean[2], c.mean[3]];
 
             let weight = c.weight;
@@ -30,55 +74,131 @@ impl Colormap {
         });
 
         let entries_sl = &mut entries[..size];
-        let mut error;
+        let (mut error, mut sq_error_sum);
 
         let mut tree = vpsearch::SearchTree::new(entries_sl, &weights);
-        (error, weights) = kmeans(clusters, entries_sl, &tree, total_weight);
+        (error, sq_error_sum, weights) =
+            kmeans(clusters, entries_sl, fixed.len(), &tree, total_weight);
+
+        for _ in 1..refinement.max_passes {
+            if error <= 0.001 {
+                break;
+            }
 
-        if error > 0.001 {
+            let prev_error = error;
+
+            tree = vpsearch::SearchTree::new(entries_sl, &weights);
+            (error, sq_error_sum, weights) =
+                kmeans(clusters, entries_sl, fixed.len(), &tree, total_weight);
+
+            if prev_error - error < prev_error * refinement.convergence {
+                break;
+            }
+        }
+
+        if refinement.reseed && reseed_unused(clusters, entries_sl, fixed.len(), &weights) {
             tree = vpsearch::SearchTree::new(entries_sl, &weights);
-            (error, weights) = kmeans(clusters, entries_sl, &tree, total_weight);
+            (error, sq_error_sum, weights) =
+                kmeans(clusters, entries_sl, fixed.len(), &tree, total_weight);
         }
 
-        round_and_clamp_colors(entries_sl);
-        sort_colors(entries_sl, &mut weights);
+        let mut colormap = Self::new(entries_sl, fixed, &mut weights, space, error);
+        colormap.sq_error_sum = sq_error_sum;
+        colormap
+    }
+
+    pub(crate) fn from_entries(
+        color_entries: &[ColorEntry],
+        fixed: &[Color],
+        space: &WorkingSpace,
+    ) -> Self {
+        let mut entries = [[0f32; 4]; 256];
+        let mut weights = [0f32; 256];
+
+        for (e, c) in entries.iter_mut().zip(fixed) {
+            *e = space.rgba_to_working(space.palette_to_rgba(c));
+        }
+
+        let mut size = fixed.len();
+
+        for e in color_entries {
+            // Colors that are already in the palette don't need extra entries
+            let pal_color = space.rgba_to_palette(space.working_to_rgba(e.color));
+            if let Some(i) = fixed.iter().position(|c| *c == pal_color) {
+                weights[i] += e.weight;
+                continue;
+            }
 
-        tree = vpsearch::SearchTree::new(entries_sl, &weights);
+            assert!(size < 256);
+
+            entries[size] = e.color;
+            weights[size] = e.weight;
+            size += 1;
+        }
+
+        Self::new(&entries[..size], fixed, &mut weights, space, 0f32)
+    }
+
+    /// Creates a [`Colormap`] from an existing palette. Colors are kept in
+    /// the provided order
+    pub(crate) fn from_palette(palette: &Palette, space: &WorkingSpace) -> Self {
+        let size = palette.count as usize;
+        let weights = [1f32; 256];
+        let mut tree_entries = [[0f32; 4]; 256];
+
+        for (te, pe) in tree_entries.iter_mut().zip(&palette.entries[..size]) {
+            *te = space.rgba_to_working(space.palette_to_rgba(pe));
+        }
 
         Self {
-            palette: entries[..size].into(),
-            tree,
-            error,
+            palette: Palette {
+                count: palette.count,
+                entries: palette.entries,
+            },
+            tree: vpsearch::SearchTree::new(&tree_entries[..size], &weights),
+            error: 0f32,
+            sq_error_sum: 0f32,
         }
     }
 
-    pub(crate) fn from_histogram(hist: &Histogram) -> Self {
-        assert!(hist.map.len() <= 256);
+    /// Creates a [`Colormap`] from colors in the working space.
+    /// Colors are converted to the palette ones, and the search tree is built
+    /// from the palette colors so remapping picks exactly what's in the palette.
+    /// The first colors are replaced with the fixed ones as is
+    fn new(
+        colors: &[[f32; 4]],
+        fixed: &[Color],
+        weights: &mut [f32],
+        space: &WorkingSpace,
+        error: f32,
+    ) -> Self {
+        let size = colors.len();
+        let mut palette_entries = [Color::default(); 256];
+
+        for (pe, c) in palette_entries.iter_mut().zip(colors) {
+            *pe = space.rgba_to_palette(space.working_to_rgba(*c));
+        }
 
-        let size = hist.map.len();
-        let mut entries = [[0f32; 4]; 256];
-        let mut weights = [0f32; 256];
+        palette_entries[..fixed.len()].copy_from_slice(fixed);
 
-        hist.map.values().enumerate().for_each(|(i, e)| {
-            entries[i] = [
-                e.color[0] as f32,
-                e.color[1] as f32,
-                e.color[2] as f32,
-                e.color[3] as f32,
-            ];
-            weights[i] = e.weight as f32;
-        });
+        sort_colors(&mut palette_entries[..size], weights);
 
-        let entries_sl = &mut entries[..size];
+        let mut tree_entries = [[0f32; 4]; 256];
 
-        sort_colors(entries_sl, &mut weights);
+        for (te, pe) in tree_entries.iter_mut().zip(&palette_entries[..size]) {
+            *te = space.rgba_to_working(space.palette_to_rgba(pe));
+        }
 
-        let tree = vpsearch::SearchTree::new(entries_sl, &weights);
+        let tree = vpsearch::SearchTree::new(&tree_entries[..size], weights);
 
         Self {
-            palette: entries[..size].into(),
+            palette: Palette {
+                count: size as u32,
+                entries: palette_entries,
+            },
             tree,
-            error: 0f32,
+            error,
+            sq_error_sum: 0f32,
         }
     }
 
@@ -92,38 +212,41 @@ impl Colormap {
     }
 }
 
+/// Moves the palette entries to the means of the colors nearest to them.
+/// The first `fixed` entries are never moved.
+/// Returns the mean squared error of the pixels, the sum of squared errors of
+/// the colors, and the weights of the entries
 fn kmeans(
     clusters: &Vec<Cluster>,
     entries: &mut [[f32; 4]],
+    fixed: usize,
     tree: &vpsearch::SearchTree,
     total_weight: f32,
-) -> (f32, [f32; 256]) {
+) -> (f32, f32, [f32; 256]) {
     let mut colors = [[0f32; 4]; 256];
     let mut weights = [0f32; 256];
 
     let mut total_err = 0f32;
+    let mut total_sq_err = 0f64;
 
     for cluster in clusters.iter() {
         for entry in cluster.entries.iter() {
-            let hist_color = [
-                entry.color[0] as f32,
-                entry.color[1] as f32,
-                entry.color[2] as f32,
-                entry.color[3] as f32,
-            ];
-            let weight = entry.weight as f32;
+            let weight = entry.weight;
 
-            let (ind, _, err) = tree.find_nearest(&hist_color);
+            let (ind, _, err) = tree.find_nearest(&entry.color);
 
             let color = &mut colors[usize::from(ind)];
-            add_color(color, &hist_color, weight);
+            add_color(color, &entry.color, weight);
 
             weights[ind as usize] += weight;
             total_err += err * err;
+            total_sq_err += (err * err * weight) as f64;
         }
     }
 
-    for ((ec, c), weight) in entries.iter_mut().zip(colors).zip(weights) {
+    let movable = entries.iter_mut().zip(colors).zip(weights).skip(fixed);
+
+    for ((ec, c), weight) in movable {
         if weight > 0.0 {
             ec[0] = c[0] / weight;
             ec[1] = c[1] / weight;
@@ -132,25 +255,64 @@ fn kmeans(
         }
     }
 
-    (total_err / total_weight, weights)
+    (
+        (total_sq_err / total_weight as f64) as f32,
+        total_err,
+        weights,
+    )
 }
 
-fn round_and_clamp_colors(entries: &mut [[f32; 4]]) {
-    for entry in entries.iter_mut() {
-        entry[0] = entry[0].round().clamp(0.0, 255.0);
-        entry[1] = entry[1].round().clamp(0.0, 255.0);
-        entry[2] = entry[2].round().clamp(0.0, 255.0);
-        entry[3] = entry[3].round().clamp(0.0, 255.0);
+/// Moves palette entries that no color is mapped to, to the colors with
+/// the largest error. Returns `true` if any entry was moved
+fn reseed_unused(
+    clusters: &Vec<Cluster>,
+    entries: &mut [[f32; 4]],
+    fixed: usize,
+    weights: &[f32],
+) -> bool {
+    let unused: Vec<usize> = (fixed..entries.len())
+        .filter(|&i| weights[i] <= 0.0)
+        .collect();
+
+    if unused.is_empty() {
+        return false;
+    }
+
+    let tree = vpsearch::SearchTree::new(entries, weights);
+
+    let mut worst: Vec<(f32, [f32; 4])> = clusters
+        .iter()
+        .flat_map(|c| c.entries.iter())
+        .map(|e| {
+            let (_, _, err) = tree.find_nearest(&e.color);
+            (err * err * e.weight, e.color)
+        })
+        .filter(|&(err, _)| err > 0.0)
+        .collect();
+
+    let count = unused.len().min(worst.len());
+    if count == 0 {
+        return false;
     }
+
+    worst.select_nth_unstable_by_key(count - 1, |&(err, _)| {
+        std::cmp::Reverse(OrdFloat32::from(err))
+    });
+
+    for (&i, &(_, color)) in unused.iter().zip(&worst[..count]) {
+        entries[i] = color;
+    }
+
+    true
 }
 
 /// Sort colors by alpha channel for better PNG compression.
 /// Weights are sorted along with the colors.
-fn sort_colors(entries: &mut [[f32; 4]], weights: &mut [f32]) {
+fn sort_colors(entries: &mut [Color], weights: &mut [f32]) {
     assert!(weights.len() >= entries.len());
 
     let mut indexes: Vec<usize> = (0..entries.len()).collect();
-    indexes.sort_by_cached_key(|&i| OrdFloat32::from(entries[i][3]));
+    indexes.sort_by_cached_key(|&i| entries[i].a);
 
     for i in 0..indexes.len() {
         if indexes[i] != i {
@@ -173,7 +335,7 @@ fn sort_colors(entries: &mut [[f32; 4]], weights: &mut [f32]) {
         }
     }
 
-    // entries.sort_unstable_by_key(|e| OrdFloat32::from(e[3]));
+    // entries.sort_unstable_by_key(|e| e.a);
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -221,3 +383,41 @@ fn add_color(dst: &mut [f32; 4], src: &[f32; 4], weight: f32) {
     dst[2] += src[2] * weight;
     dst[3] += src[3] * weight;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colormap_refinement() {
+        let efforts: Vec<_> = (1..=10)
+            .map(|speed| {
+                let r = Refinement::new(speed);
+                (r.max_passes, r.reseed)
+            })
+            .collect();
+
+        assert_eq!(
+            efforts,
+            [
+                (16, true),
+                (8, true),
+                (4, false),
+                (2, false),
+                (2, false),
+                (2, false),
+                (2, false),
+                (1, false),
+                (1, false),
+                (1, false),
+            ]
+        );
+
+        // Slower speeds keep refining on smaller improvements, while faster
+        // ones run all their passes unless the error stops improving
+        assert!(Refinement::new(1).convergence < Refinement::new(2).convergence);
+        assert!(Refinement::new(2).convergence < Refinement::new(3).convergence);
+        assert_eq!(Refinement::new(4).convergence, 0.0);
+        assert_eq!(Refinement::new(10).convergence, 0.0);
+    }
+}
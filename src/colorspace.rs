@@ -0,0 +1,362 @@
+use crate::options::{ColorSpace, LinearLight, Options};
+use crate::palette::Color;
+
+/// Color in the working space along with its weight
+pub(crate) struct ColorEntry {
+    pub color: [f32; 4],
+    pub weight: f32,
+}
+
+/// Converts colors between straight RGBA and the space the quantization
+/// works in
+pub(crate) struct WorkingSpace {
+    oklab: bool,
+    linear_light: LinearLight,
+    // Lookup table for linearization. Has an extra entry for interpolation
+    linear_lut: Vec<f32>,
+    premultiplied: bool,
+    // Channels are scaled by square roots of their weights so the squared
+    // distance between colors is weighted. Empty if all weights are 1.0
+    channel_scales: Vec<f32>,
+    premultiplied_palette: bool,
+    gray_palette: bool,
+}
+
+impl WorkingSpace {
+    pub(crate) fn new(attr: &Options) -> Self {
+        let oklab = attr.get_color_space() == ColorSpace::Oklab;
+
+        // Oklab conversion requires linear light
+        let linear_light = match attr.get_linear_light() {
+            LinearLight::Disabled if oklab => LinearLight::Srgb,
+            linear_light => linear_light,
+        };
+
+        let linear_lut = if linear_light == LinearLight::Disabled {
+            Vec::new()
+        } else {
+            let mut lut: Vec<f32> = (0..256)
+                .map(|v| to_linear(linear_light, v as f32 / 255.0) * 255.0)
+                .collect();
+            lut.push(255.0);
+            lut
+        };
+
+        let weights = attr.get_channel_weights();
+        let channel_scales = if weights == [1.0; 4] {
+            Vec::new()
+        } else {
+            weights.iter().map(|w| w.sqrt()).collect()
+        };
+
+        Self {
+            oklab,
+            linear_light,
+            linear_lut,
+            premultiplied: attr.get_premultiplied_alpha(),
+            channel_scales,
+            premultiplied_palette: attr.get_premultiplied_palette(),
+            gray_palette: false,
+        }
+    }
+
+    /// Sets whether palette colors should be forced to be neutral gray
+    pub(crate) fn set_gray_palette(&mut self, gray: bool) {
+        self.gray_palette = gray;
+    }
+
+    /// Converts straight RGBA color to the working space
+    #[inline(always)]
+    pub(crate) fn rgba_to_working(&self, mut color: [f32; 4]) -> [f32; 4] {
+        if !self.linear_lut.is_empty() {
+            color[0] = self.linearize(color[0]);
+            color[1] = self.linearize(color[1]);
+            color[2] = self.linearize(color[2]);
+        }
+
+        if self.oklab {
+            color = linear_to_oklab(color);
+        }
+
+        if self.premultiplied {
+            color = premultiply(color);
+        }
+
+        for (c, scale) in color.iter_mut().zip(&self.channel_scales) {
+            *c *= scale;
+        }
+
+        color
+    }
+
+    /// Converts color from the working space to straight RGBA
+    #[inline(always)]
+    pub(crate) fn working_to_rgba(&self, mut color: [f32; 4]) -> [f32; 4] {
+        for (c, scale) in color.iter_mut().zip(&self.channel_scales) {
+            *c /= scale;
+        }
+
+        if self.premultiplied {
+            color = unpremultiply(color);
+        }
+
+        if self.oklab {
+            color = oklab_to_linear(color);
+        }
+
+        if self.linear_light != LinearLight::Disabled {
+            for c in color[..3].iter_mut() {
+                *c = from_linear(self.linear_light, (*c / 255.0).clamp(0.0, 1.0)) * 255.0;
+            }
+        }
+
+        color
+    }
+
+    /// Converts a channel value to linear light using the lookup table.
+    /// Fractional values are interpolated
+    #[inline(always)]
+    fn linearize(&self, v: f32) -> f32 {
+        let v = v.clamp(0.0, 255.0);
+        let i = v as usize;
+        let frac = v - i as f32;
+
+        let lo = self.linear_lut[i];
+        let hi = self.linear_lut[i + 1];
+
+        lo + (hi - lo) * frac
+    }
+
+    /// Converts straight RGBA color to a palette color
+    pub(crate) fn rgba_to_palette(&self, mut color: [f32; 4]) -> Color {
+        if self.gray_palette {
+            let gray = (color[0] + color[1] + color[2]) / 3.0;
+            color = [gray, gray, gray, color[3]];
+        }
+
+        let color = if self.premultiplied_palette {
+            premultiply(color)
+        } else {
+            color
+        };
+
+        Color {
+            r: color[0].round().clamp(0.0, 255.0) as u8,
+            g: color[1].round().clamp(0.0, 255.0) as u8,
+            b: color[2].round().clamp(0.0, 255.0) as u8,
+            a: color[3].round().clamp(0.0, 255.0) as u8,
+        }
+    }
+
+    /// Converts palette color to straight RGBA
+    pub(crate) fn palette_to_rgba(&self, color: &Color) -> [f32; 4] {
+        let color = [
+            color.r as f32,
+            color.g as f32,
+            color.b as f32,
+            color.a as f32,
+        ];
+
+        if self.premultiplied_palette {
+            unpremultiply(color)
+        } else {
+            color
+        }
+    }
+}
+
+/// Converts a `0.0..=1.0` channel value to linear light
+fn to_linear(linear_light: LinearLight, v: f32) -> f32 {
+    match linear_light {
+        LinearLight::Disabled => v,
+        LinearLight::Srgb => {
+            if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        }
+        LinearLight::Gamma(gamma) => v.powf(gamma),
+    }
+}
+
+/// Converts a `0.0..=1.0` linear light channel value back
+fn from_linear(linear_light: LinearLight, v: f32) -> f32 {
+    match linear_light {
+        LinearLight::Disabled => v,
+        LinearLight::Srgb => {
+            if v <= 0.0031308 {
+                v * 12.92
+            } else {
+                1.055 * v.powf(1.0 / 2.4) - 0.055
+            }
+        }
+        LinearLight::Gamma(gamma) => v.powf(1.0 / gamma),
+    }
+}
+
+/// Converts linear RGBA color to Oklab. Both colors are scaled to 0-255 range.
+/// The math is done in `f64` so converting colors back and forth doesn't
+/// shift dark colors when a gamma curve is applied on top
+#[inline(always)]
+fn linear_to_oklab(color: [f32; 4]) -> [f32; 4] {
+    let [r, g, b] = [
+        color[0] as f64 / 255.0,
+        color[1] as f64 / 255.0,
+        color[2] as f64 / 255.0,
+    ];
+
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    [
+        ((0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s) * 255.0) as f32,
+        ((1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s) * 255.0) as f32,
+        ((0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s) * 255.0) as f32,
+        color[3],
+    ]
+}
+
+/// Converts Oklab color to linear RGBA. Both colors are scaled to 0-255 range
+#[inline(always)]
+fn oklab_to_linear(color: [f32; 4]) -> [f32; 4] {
+    let [ll, a, b] = [
+        color[0] as f64 / 255.0,
+        color[1] as f64 / 255.0,
+        color[2] as f64 / 255.0,
+    ];
+
+    let l = (ll + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (ll - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (ll - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+
+    [
+        ((4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s) * 255.0).clamp(0.0, 255.0) as f32,
+        ((-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s) * 255.0).clamp(0.0, 255.0)
+            as f32,
+        ((-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s) * 255.0).clamp(0.0, 255.0)
+            as f32,
+        color[3],
+    ]
+}
+
+#[inline(always)]
+pub(crate) fn premultiply(color: [f32; 4]) -> [f32; 4] {
+    let k = color[3] / 255.0;
+
+    [color[0] * k, color[1] * k, color[2] * k, color[3]]
+}
+
+#[inline(always)]
+pub(crate) fn unpremultiply(color: [f32; 4]) -> [f32; 4] {
+    if color[3] <= 0.0 {
+        return [0.0; 4];
+    }
+
+    let k = 255.0 / color[3];
+
+    [
+        (color[0] * k).min(255.0),
+        (color[1] * k).min(255.0),
+        (color[2] * k).min(255.0),
+        color[3],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colorspace_roundtrip() {
+        let modes = [
+            LinearLight::Disabled,
+            LinearLight::Srgb,
+            LinearLight::Gamma(2.2),
+        ];
+
+        for linear_light in modes {
+            for premultiplied in [false, true] {
+                let mut opts = Options::default();
+                opts.set_channel_weights([0.5, 1.0, 2.0, 4.0]).unwrap();
+                opts.set_linear_light(linear_light).unwrap();
+                opts.set_premultiplied_alpha(premultiplied);
+
+                let space = WorkingSpace::new(&opts);
+
+                for v in 0..=255 {
+                    let color = [v as f32, (255 - v) as f32, 128.0, 200.0];
+                    let back = space.working_to_rgba(space.rgba_to_working(color));
+
+                    for (c, b) in color.iter().zip(back) {
+                        assert!((c - b).abs() < 0.01, "{:?} {:?}", linear_light, color);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_colorspace_oklab_roundtrip() {
+        let modes = [
+            LinearLight::Disabled,
+            LinearLight::Srgb,
+            LinearLight::Gamma(2.2),
+        ];
+
+        for linear_light in modes {
+            for premultiplied in [false, true] {
+                let mut opts = Options::default();
+                opts.set_channel_weights([0.5, 1.0, 2.0, 4.0]).unwrap();
+                opts.set_linear_light(linear_light).unwrap();
+                opts.set_color_space(ColorSpace::Oklab);
+                opts.set_premultiplied_alpha(premultiplied);
+
+                let space = WorkingSpace::new(&opts);
+
+                for v in 0..=255 {
+                    let color = [v as f32, (255 - v) as f32, 128.0, 200.0];
+                    let back = space.working_to_rgba(space.rgba_to_working(color));
+
+                    // Oklab uses a cube root that is steep near zero, so we
+                    // only check that the difference doesn't affect rounding
+                    // to 8 bits
+                    for (c, b) in color.iter().zip(back) {
+                        assert!((c - b).abs() < 0.25, "{:?} {:?}", linear_light, color);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_colorspace_oklab_nearest_color() {
+        let gray = [128.0, 128.0, 128.0, 255.0];
+        // Green shift is smaller in RGB, but the eye is much more sensitive
+        // to it than to the blue one
+        let candidates = [[128.0, 158.0, 128.0, 255.0], [128.0, 128.0, 168.0, 255.0]];
+
+        let nearest = |color_space| {
+            let mut opts = Options::default();
+            opts.set_color_space(color_space);
+            let space = WorkingSpace::new(&opts);
+
+            let target = space.rgba_to_working(gray);
+            (0..candidates.len())
+                .min_by(|&i, &j| {
+                    let di = dist_sq(target, space.rgba_to_working(candidates[i]));
+                    let dj = dist_sq(target, space.rgba_to_working(candidates[j]));
+                    di.total_cmp(&dj)
+                })
+                .unwrap()
+        };
+
+        assert_eq!(nearest(ColorSpace::Rgb), 0);
+        assert_eq!(nearest(ColorSpace::Oklab), 1);
+    }
+
+    fn dist_sq(a: [f32; 4], b: [f32; 4]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+}
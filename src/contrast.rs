@@ -0,0 +1,275 @@
+use crate::image::Image;
+
+/// Per-pixel maps based on the local contrast of the image
+pub(crate) struct ContrastMaps {
+    /// Low in noisy areas, high in smooth areas and along edges
+    pub noise: Vec<u8>,
+    /// Low in noisy areas and along edges where dithering is not desirable
+    pub dither: Vec<u8>,
+}
+
+// Weight of the most noisy pixels. Colors of noise still need to be
+// represented, just not as precisely as colors of smooth areas
+const MIN_NOISE_WEIGHT: f32 = 0.25;
+// How fast the weight drops with texture. Texture of 0.1 halves the weight
+const TEXTURE_FALLOFF: f32 = 10.0;
+
+impl ContrastMaps {
+    /// Computes contrast maps of the image. Returns `None` if the image is
+    /// too small for the maps to be useful
+    pub(crate) fn new(image: &Image) -> Option<Self> {
+        let (width, height) = (image.width, image.height);
+
+        if width < 3 || height < 3 {
+            return None;
+        }
+
+        // Texture is the contrast in both directions, while an edge has
+        // contrast in at least one of them
+        let mut texture = vec![0f32; width * height];
+        let mut edges = vec![0f32; width * height];
+
+        let mut prev = vec![[0f32; 4]; width];
+        let mut curr = vec![[0f32; 4]; width];
+        let mut next = vec![[0f32; 4]; width];
+
+        image.read_row_f32(0, &mut curr);
+        prev.copy_from_slice(&curr);
+
+        for y in 0..height {
+            if y + 1 < height {
+                image.read_row_f32(y + 1, &mut next);
+            } else {
+                next.copy_from_slice(&curr);
+            }
+
+            for x in 0..width {
+                let left = &curr[x.saturating_sub(1)];
+                let right = &curr[(x + 1).min(width - 1)];
+
+                let horiz = contrast(left, &curr[x], right);
+                let vert = contrast(&prev[x], &curr[x], &next[x]);
+
+                let i = y * width + x;
+                texture[i] = horiz.min(vert);
+                edges[i] = horiz.max(vert);
+            }
+
+            std::mem::swap(&mut prev, &mut curr);
+            std::mem::swap(&mut curr, &mut next);
+        }
+
+        // Noise is texture that covers an area, so lone pixels of smooth
+        // areas surrounded by noise get the texture of their neighbours
+        let texture = box_blur3(&texture, width, height);
+
+        let mut noise = vec![0u8; width * height];
+        let mut dither = vec![0u8; width * height];
+
+        for (((n, d), t), e) in noise.iter_mut().zip(&mut dither).zip(texture).zip(edges) {
+            let smoothness = 1.0 / (1.0 + TEXTURE_FALLOFF * t);
+            let weight = MIN_NOISE_WEIGHT + (1.0 - MIN_NOISE_WEIGHT) * smoothness;
+
+            *n = (weight * 255.0).round() as u8;
+            // Dithering is visible as noise along edges too
+            *d = (smoothness * (1.0 - e) * 255.0).round() as u8;
+        }
+
+        Some(Self { noise, dither })
+    }
+}
+
+/// Returns the largest second derivative of the color channels in 0-1 range
+#[inline(always)]
+fn contrast(prev: &[f32; 4], curr: &[f32; 4], next: &[f32; 4]) -> f32 {
+    let mut max = 0f32;
+
+    for c in 0..4 {
+        max = max.max((prev[c] + next[c] - curr[c] * 2.0).abs());
+    }
+
+    (max / 255.0).min(1.0)
+}
+
+/// Averages every value with its neighbours in a 3x3 window. Edge values
+/// are repeated outside the map
+fn box_blur3(src: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut horiz = vec![0f32; width * height];
+
+    for (src_row, dst_row) in src.chunks_exact(width).zip(horiz.chunks_exact_mut(width)) {
+        for (x, d) in dst_row.iter_mut().enumerate() {
+            let left = src_row[x.saturating_sub(1)];
+            let right = src_row[(x + 1).min(width - 1)];
+
+            *d = (left + src_row[x] + right) / 3.0;
+        }
+    }
+
+    let mut dst = vec![0f32; width * height];
+
+    for y in 0..height {
+        let up = y.saturating_sub(1) * width;
+        let row = y * width;
+        let down = (y + 1).min(height - 1) * width;
+
+        for x in 0..width {
+            dst[row + x] = (horiz[up + x] + horiz[row + x] + horiz[down + x]) / 3.0;
+        }
+    }
+
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::histogram::Histogram;
+    use crate::options::Options;
+    use crate::palette::{Color, Palette};
+    use crate::quantize::QuantizeResult;
+
+    #[test]
+    fn test_contrast_maps_noise() {
+        let (width, height) = (16, 8);
+        let mut data = vec![255u8; width * height * 3];
+
+        // Left half is a checkerboard, right half is flat gray
+        for y in 0..height {
+            for x in 0..width {
+                let v = if x < width / 2 && (x + y) % 2 == 0 {
+                    0
+                } else {
+                    128
+                };
+                data[(y * width + x) * 3..][..3].fill(v);
+            }
+        }
+
+        let image = Image::new_rgb(&data, width, height).unwrap();
+        let maps = ContrastMaps::new(&image).unwrap();
+
+        let noisy = maps.noise[3 * width + 2];
+        let smooth = maps.noise[3 * width + 13];
+
+        assert!(noisy < smooth, "{} {}", noisy, smooth);
+        assert!(maps.dither[3 * width + 2] < maps.dither[3 * width + 13]);
+    }
+
+    // Gray image with a noisy left half and a smooth gradient on the right.
+    // Noise is between the palette colors, so dithering mixes them
+    fn noisy_and_smooth(width: usize, height: usize) -> Vec<u8> {
+        let mut data = vec![0u8; width * height * 3];
+
+        for y in 0..height {
+            for x in 0..width {
+                let v = if x < width / 2 {
+                    (100 + (x * 7 + y * 13) * 37 % 41) as u8
+                } else {
+                    ((x - width / 2) * 255 / (width / 2)) as u8
+                };
+                data[(y * width + x) * 3..][..3].fill(v);
+            }
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_contrast_maps_histogram_weights() {
+        let (width, height) = (16, 8);
+        let mut data = vec![0u8; width * height * 3];
+
+        // Left half is a black and white checkerboard, right half is flat gray
+        for y in 0..height {
+            for x in 0..width {
+                let v = match x < width / 2 {
+                    true if (x + y) % 2 == 0 => 0,
+                    true => 255,
+                    false => 128,
+                };
+                data[(y * width + x) * 3..][..3].fill(v);
+            }
+        }
+
+        let image = Image::new_rgb(&data, width, height).unwrap();
+
+        // Every color covers a quarter of the image
+        let weight_of = |hist: &Histogram, v: u8| {
+            hist.iter()
+                .find(|(c, _)| c.r == v)
+                .map(|(_, w)| w / (width * height / 4) as f64)
+                .unwrap()
+        };
+
+        let mut opts = Options::default();
+        let mut hist = Histogram::new();
+        hist.add_image_with_options(&image, &opts);
+
+        assert_eq!(weight_of(&hist, 0), 1.0);
+        assert_eq!(weight_of(&hist, 128) / 2.0, 1.0);
+
+        opts.set_contrast_maps(true);
+        let mut hist = Histogram::new();
+        hist.add_image_with_options(&image, &opts);
+
+        let noisy = weight_of(&hist, 0);
+        let smooth = weight_of(&hist, 128) / 2.0;
+
+        assert!(noisy < smooth * 0.5, "{} {}", noisy, smooth);
+    }
+
+    #[test]
+    fn test_contrast_maps_dithering() {
+        let (width, height) = (64, 32);
+        let data = noisy_and_smooth(width, height);
+        let image = Image::new_rgb(&data, width, height).unwrap();
+
+        let mut palette = Palette {
+            count: 4,
+            entries: [Color::default(); 256],
+        };
+        for (i, c) in palette.entries[..4].iter_mut().enumerate() {
+            let v = (i * 85) as u8;
+            *c = Color {
+                r: v,
+                g: v,
+                b: v,
+                a: 255,
+            };
+        }
+
+        // Counts pixels of the left and the right half that dithering moved
+        // from their nearest color
+        let dithered_counts = |contrast_maps| {
+            let mut opts = Options::default();
+            opts.set_contrast_maps(contrast_maps);
+
+            let mut result = QuantizeResult::from_palette(&palette, &opts).unwrap();
+
+            let mut dithered = vec![0u8; width * height];
+            result.remap_image(&image, &mut dithered).unwrap();
+
+            result.set_dithering_level(0.0).unwrap();
+            let mut nearest = vec![0u8; width * height];
+            result.remap_image(&image, &mut nearest).unwrap();
+
+            let mut counts = (0, 0);
+            for (i, (d, n)) in dithered.iter().zip(&nearest).enumerate() {
+                if d != n {
+                    match i % width < width / 2 {
+                        true => counts.0 += 1,
+                        false => counts.1 += 1,
+                    }
+                }
+            }
+            counts
+        };
+
+        let (noisy, smooth) = dithered_counts(false);
+        let (noisy_maps, smooth_maps) = dithered_counts(true);
+
+        assert!(noisy_maps * 2 < noisy, "{} {}", noisy_maps, noisy);
+        // Smooth areas are still dithered
+        assert!(smooth_maps * 2 > smooth, "{} {}", smooth_maps, smooth);
+    }
+}
@@ -6,6 +6,8 @@ pub enum Error {
     ValueOutOfRange,
     /// The slice provided to the function is too small
     BufferTooSmall,
+    /// The quality of the quantization result is lower than the minimum
+    QualityTooLow,
 }
 
 impl fmt::Display for Error {
@@ -13,6 +15,7 @@ impl fmt::Display for Error {
         match self {
             Self::ValueOutOfRange => write!(f, "Value out of range"),
             Self::BufferTooSmall => write!(f, "Buffer is too small"),
+            Self::QualityTooLow => write!(f, "Quality is too low"),
         }
     }
 }
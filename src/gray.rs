@@ -0,0 +1,31 @@
+use crate::cluster::Cluster;
+use crate::colorspace::{ColorEntry, WorkingSpace};
+use crate::moments;
+
+/// Splits gray colors into clusters. Gray colors only vary by the gray level
+/// and alpha, so instead of splitting clusters in 4-D we split boxes of
+/// a 2-D (gray level, alpha) grid, which is much faster.
+/// See [`moments::split_boxes`].
+///
+/// Entries should be in the working space and be gray in the straight RGBA
+pub(crate) fn split_gray<'clust>(
+    entries: &'clust [ColorEntry],
+    space: &WorkingSpace,
+    max_colors: usize,
+    target_error: f32,
+) -> Vec<Cluster<'clust>> {
+    let levels: Vec<[usize; 2]> = entries
+        .iter()
+        .map(|e| {
+            let c = space.working_to_rgba(e.color);
+            let v = (c[0] + c[1] + c[2]) / 3.0;
+
+            [
+                v.round().clamp(0.0, 255.0) as usize,
+                c[3].round().clamp(0.0, 255.0) as usize,
+            ]
+        })
+        .collect();
+
+    moments::split_boxes(entries, &levels, [256, 256], max_colors, target_error)
+}
@@ -1,16 +1,38 @@
 use std::collections::HashMap;
 use std::hash::{BuildHasher, Hasher};
 
+use crate::colorspace::{ColorEntry, WorkingSpace};
+use crate::contrast::ContrastMaps;
+use crate::error::Error;
 use crate::image::Image;
+use crate::options::Options;
+use crate::palette::Color;
 
 pub(crate) struct HistogramEntry {
-    pub color: [u8; 4],
-    pub weight: u32,
+    /// Weighted mean of the colors that fell into this entry
+    pub color: [f32; 4],
+    pub weight: f64,
 }
 
+// Histogram can't have more entries than this limit allows at 2 bits
+// per channel
+const MIN_MAX_ENTRIES: usize = 256;
+
+// Clustering sums weights and weighted colors in f32, so the total weight
+// is normalized if it's out of this range. The scale of the weights doesn't
+// affect the result
+const MIN_TOTAL_WEIGHT: f64 = 1e-20;
+const MAX_TOTAL_WEIGHT: f64 = 1e20;
+
 /// Color histogram
 pub struct Histogram {
     pub(crate) map: HashMap<u64, HistogramEntry, ColorHasher>,
+    // Colors are grouped by this number of the most significant bits of
+    // every channel
+    bits: u8,
+    max_entries: usize,
+    // Number of added pixels and colors regardless of their weights
+    pub(crate) pixels: usize,
 }
 
 impl Histogram {
@@ -18,11 +40,167 @@ impl Histogram {
     pub fn new() -> Self {
         Self {
             map: HashMap::with_hasher(ColorHasher(0)),
+            bits: 8,
+            max_entries: usize::MAX,
+            pixels: 0,
+        }
+    }
+
+    /// Sets the number of bits per channel used to group colors. Fewer bits
+    /// mean fewer entries, which makes quantization of noisy images faster.
+    /// Entries keep the weighted means of their colors, so palette colors
+    /// aren't snapped to the coarse grid. 8 by default.
+    ///
+    /// Existing entries are merged if the number of bits is reduced.
+    /// The precision can't be restored once colors are added, so more bits
+    /// than the histogram currently has only affect an empty histogram.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided number of bits is
+    /// greater than 8 or less than 1
+    pub fn set_bits_per_channel(&mut self, bits: u8) -> Result<(), Error> {
+        if !(1..=8).contains(&bits) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        if self.map.is_empty() {
+            self.bits = bits;
+        } else if bits < self.bits {
+            self.reduce_bits(bits);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the maximum number of entries. When the histogram gets more
+    /// entries, the number of bits per channel is reduced until it fits.
+    /// 0 means no limit, which is the default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided number is less
+    /// than 256 and isn't 0
+    pub fn set_max_entries(&mut self, max_entries: usize) -> Result<(), Error> {
+        if max_entries == 0 {
+            self.max_entries = usize::MAX;
+            return Ok(());
         }
+
+        if max_entries < MIN_MAX_ENTRIES {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.max_entries = max_entries;
+        self.limit_entries();
+
+        Ok(())
     }
 
-    /// Adds colors from [`Image`] to the histogram
+    /// Adds colors from [`Image`] to the histogram.
+    /// If the image has an importance map, colors are weighted by it
     pub fn add_image(&mut self, image: &Image) {
+        self.add_image_with_noise(image, None, 1.0);
+    }
+
+    /// Adds colors from [`Image`] to the histogram scaling their weights by
+    /// `weight`. This allows balancing images when building a shared palette.
+    ///
+    /// If `normalize` is `true`, the total weight of the image colors is
+    /// `weight` regardless of the image size, so images added with the same
+    /// weight contribute equally. Otherwise, every pixel gets `weight`.
+    ///
+    /// If the image has an importance map, colors are weighted by it too.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the weight is negative or
+    /// not finite
+    pub fn add_image_weighted(
+        &mut self,
+        image: &Image,
+        weight: f64,
+        normalize: bool,
+    ) -> Result<(), Error> {
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        let scale = if normalize {
+            let total = if image.get_importance_map().is_some() {
+                (0..image.height)
+                    .filter_map(|y| image.importance_row(y))
+                    .flat_map(|row| row.iter().map(|&imp| imp as f64 / 255.0))
+                    .sum()
+            } else {
+                (image.width * image.height) as f64
+            };
+
+            if total > 0.0 { weight / total } else { 0.0 }
+        } else {
+            weight
+        };
+
+        if scale > 0.0 {
+            self.add_image_with_noise(image, None, scale);
+        }
+
+        Ok(())
+    }
+
+    /// Adds a straight (not premultiplied) RGBA color with the provided
+    /// weight to the histogram. Colors with zero weight are ignored.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the weight is negative or
+    /// not finite
+    pub fn add_color(&mut self, color: Color, weight: f64) -> Result<(), Error> {
+        self.add_colors(&[(color, weight)])
+    }
+
+    /// Adds straight (not premultiplied) RGBA colors with their weights to
+    /// the histogram. Colors with zero weight are ignored.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if any weight is negative or
+    /// not finite. No colors are added in this case
+    pub fn add_colors(&mut self, colors: &[(Color, f64)]) -> Result<(), Error> {
+        if colors.iter().any(|(_, w)| !w.is_finite() || *w < 0.0) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        for (c, weight) in colors {
+            if *weight > 0.0 {
+                self.add_precise_color(&[c.r, c.g, c.b, c.a].map(|v| v as f32), *weight);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds colors from [`Image`] to the histogram using the provided
+    /// [`Options`]. If contrast maps are enabled, colors of noisy areas
+    /// get less weight than colors of smooth areas and edges.
+    ///
+    /// The number of bits per channel and the maximum number of entries
+    /// set in the options are applied to the histogram if they are lower
+    /// than the histogram ones
+    pub fn add_image_with_options(&mut self, image: &Image, attr: &Options) {
+        let bits = attr.get_histogram_bits();
+        if bits < self.bits {
+            // Always in range since options validate it
+            let _ = self.set_bits_per_channel(bits);
+        }
+
+        let max_entries = attr.get_max_histogram_entries();
+        if max_entries > 0 && max_entries < self.max_entries {
+            let _ = self.set_max_entries(max_entries);
+        }
+
+        let maps = if attr.get_contrast_maps() {
+            ContrastMaps::new(image)
+        } else {
+            None
+        };
+
+        self.add_image_with_noise(image, maps.as_ref().map(|m| m.noise.as_slice()), 1.0);
+    }
+
+    /// Adds colors from [`Image`] with their weights multiplied by the noise
+    /// map and `scale`
+    fn add_image_with_noise(&mut self, image: &Image, noise: Option<&[u8]>, scale: f64) {
         let size = image.width * image.height;
 
         let to_reserve = if self.map.is_empty() {
@@ -33,22 +211,220 @@ impl Histogram {
         .min(512 * 512);
         self.map.reserve(to_reserve);
 
-        for ind in (0..size * 4).step_by(4) {
-            let pix = &image.data[ind..ind + 4];
+        // Reduced precision requires tracking mean colors
+        if image.is_exact_8bit() && self.bits == 8 && self.max_entries == usize::MAX {
+            self.add_image_exact(image, noise, scale);
+        } else {
+            self.add_image_precise(image, noise, scale);
+        }
+    }
+
+    /// Adds colors of 8-bit images. Every color gets its own entry so there's
+    /// no need to track mean values
+    fn add_image_exact(&mut self, image: &Image, noise: Option<&[u8]>, scale: f64) {
+        let mut row = vec![[0u8; 4]; image.width];
+
+        for y in 0..image.height {
+            image.read_row(y, &mut row);
+            let importance = image.importance_row(y);
+            let noise = noise.map(|n| &n[y * image.width..(y + 1) * image.width]);
+
+            for (x, pix) in row.iter().enumerate() {
+                let weight = pixel_weight(importance, noise, x) * scale;
+                if weight <= 0.0 {
+                    continue;
+                }
+
+                let mut color: [u8; 4] = [0; 4];
+                if pix[3] != 0 {
+                    color = *pix;
+                }
+
+                let key = u32::from_le_bytes(color) as u64;
+
+                self.pixels += 1;
+                self.map
+                    .entry(key)
+                    .and_modify(|e| e.weight += weight)
+                    .or_insert(HistogramEntry {
+                        color: color.map(|c| c as f32),
+                        weight,
+                    });
+            }
+        }
+    }
+
+    /// Adds colors of images that have more than 8 bits of precision or
+    /// when the histogram precision is reduced. Colors are grouped by their
+    /// reduced values, but entries keep the mean of the precise colors
+    fn add_image_precise(&mut self, image: &Image, noise: Option<&[u8]>, scale: f64) {
+        let mut row = vec![[0f32; 4]; image.width];
+
+        for y in 0..image.height {
+            image.read_row_f32(y, &mut row);
+            let importance = image.importance_row(y);
+            let noise = noise.map(|n| &n[y * image.width..(y + 1) * image.width]);
 
-            let mut color: [u8; 4] = [0; 4];
-            if pix[3] != 0 {
-                color.copy_from_slice(pix);
+            for (x, pix) in row.iter().enumerate() {
+                let weight = pixel_weight(importance, noise, x) * scale;
+                if weight <= 0.0 {
+                    continue;
+                }
+
+                self.add_precise_color(pix, weight);
             }
+        }
+    }
+
+    /// Adds the color to the entry of its reduced value
+    #[inline(always)]
+    fn add_precise_color(&mut self, pix: &[f32; 4], weight: f64) {
+        let mut key_color = pix.map(|c| c.round().clamp(0.0, 255.0) as u8);
+        let mut color = *pix;
+
+        if key_color[3] == 0 {
+            key_color = [0; 4];
+            color = [0.0; 4];
+        }
+
+        let shift = 8 - self.bits;
+        let key = u32::from_le_bytes(key_color.map(|c| c >> shift)) as u64;
+
+        self.insert(key, color, weight);
+        self.pixels += 1;
+
+        if self.map.len() > self.max_entries {
+            self.limit_entries();
+        }
+    }
 
-            let key = u32::from_le_bytes(color) as u64;
+    /// Adds the color to the entry with the provided key keeping
+    /// the weighted mean of the entry colors
+    #[inline(always)]
+    fn insert(&mut self, key: u64, color: [f32; 4], weight: f64) {
+        self.map
+            .entry(key)
+            .and_modify(|e| {
+                e.weight += weight;
+
+                let k = (weight / e.weight) as f32;
+                for (ec, c) in e.color.iter_mut().zip(color) {
+                    *ec += (c - *ec) * k;
+                }
+            })
+            .or_insert(HistogramEntry { color, weight });
+    }
 
-            self.map
-                .entry(key)
-                .and_modify(|e| e.weight = e.weight.saturating_add(1))
-                .or_insert(HistogramEntry { color, weight: 1 });
+    /// Reduces the number of bits per channel until the number of entries
+    /// fits the limit
+    fn limit_entries(&mut self) {
+        while self.map.len() > self.max_entries && self.bits > 1 {
+            self.reduce_bits(self.bits - 1);
         }
     }
+
+    /// Merges entries that have the same values at the provided number of
+    /// bits per channel
+    fn reduce_bits(&mut self, bits: u8) {
+        let shift = self.bits - bits;
+        let entries = std::mem::replace(&mut self.map, HashMap::with_hasher(ColorHasher(0)));
+
+        self.bits = bits;
+
+        for (key, e) in entries {
+            let key = u32::from_le_bytes((key as u32).to_le_bytes().map(|c| c >> shift)) as u64;
+            self.insert(key, e.color, e.weight);
+        }
+    }
+
+    /// Returns the number of entries. All fully transparent colors share
+    /// a single entry, and colors added with zero weight (like pixels with
+    /// zero importance) aren't counted. If the number of bits per channel
+    /// is reduced, similar colors share entries too
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the histogram has no colors
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns an iterator over the colors and their weights in no particular
+    /// order. Colors are straight (not premultiplied) RGBA. If the number of
+    /// bits per channel is reduced, the colors are the rounded means of
+    /// the grouped colors
+    pub fn iter(&self) -> impl Iterator<Item = (Color, f64)> + '_ {
+        self.map.values().map(|e| (entry_color(e), e.weight))
+    }
+
+    /// Returns up to `count` colors with the largest weights and their
+    /// weights, the heaviest first
+    pub fn dominant_colors(&self, count: usize) -> Vec<(Color, f64)> {
+        let mut colors: Vec<(Color, f64)> = self.iter().collect();
+
+        // Colors break ties so the order doesn't depend on the hash map
+        let cmp = |a: &(Color, f64), b: &(Color, f64)| {
+            b.1.total_cmp(&a.1).then_with(|| {
+                u32::from_le_bytes([a.0.r, a.0.g, a.0.b, a.0.a])
+                    .cmp(&u32::from_le_bytes([b.0.r, b.0.g, b.0.b, b.0.a]))
+            })
+        };
+
+        if count < colors.len() {
+            colors.select_nth_unstable_by(count, cmp);
+            colors.truncate(count);
+        }
+
+        colors.sort_unstable_by(cmp);
+        colors
+    }
+
+    /// Returns the histogram colors
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &HistogramEntry> + Clone {
+        self.map.values()
+    }
+}
+
+/// Returns the colors converted to the working space. Weights are
+/// normalized if their total doesn't fit the range clustering works with
+pub(crate) fn color_entries<'a>(
+    colors: impl Iterator<Item = &'a HistogramEntry> + Clone,
+    space: &WorkingSpace,
+) -> Vec<ColorEntry> {
+    let total_weight: f64 = colors.clone().map(|e| e.weight).sum();
+
+    let scale = if (MIN_TOTAL_WEIGHT..=MAX_TOTAL_WEIGHT).contains(&total_weight) {
+        1.0
+    } else {
+        1.0 / total_weight
+    };
+
+    colors
+        .map(|e| ColorEntry {
+            color: space.rgba_to_working(e.color),
+            weight: (e.weight * scale) as f32,
+        })
+        // Weights that are too small compared to the total can't affect
+        // the result
+        .filter(|e| e.weight > 0.0)
+        .collect()
+}
+
+/// Returns `true` if all the colors are neutral gray
+pub(crate) fn is_gray<'a>(mut colors: impl Iterator<Item = &'a HistogramEntry>) -> bool {
+    colors.all(|e| e.color[0] == e.color[1] && e.color[1] == e.color[2])
+}
+
+fn entry_color(e: &HistogramEntry) -> Color {
+    let c = e.color.map(|c| c.round().clamp(0.0, 255.0) as u8);
+
+    Color {
+        r: c[0],
+        g: c[1],
+        b: c[2],
+        a: c[3],
+    }
 }
 
 impl Default for Histogram {
@@ -57,6 +433,11 @@ impl Default for Histogram {
     }
 }
 
+#[inline(always)]
+fn pixel_weight(importance: Option<&[u8]>, noise: Option<&[u8]>, x: usize) -> f64 {
+    importance.map_or(1.0, |imp| imp[x] as f64 / 255.0) * noise.map_or(1.0, |n| n[x] as f64 / 255.0)
+}
+
 pub(crate) struct ColorHasher(u64);
 impl BuildHasher for ColorHasher {
     type Hasher = Self;
@@ -82,3 +463,146 @@ impl Hasher for ColorHasher {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_images;
+
+    #[test]
+    fn test_histogram_max_entries() {
+        let mut hist = Histogram::new();
+
+        assert!(hist.set_max_entries(100).is_err());
+        hist.set_max_entries(1024).unwrap();
+
+        let (width, height) = (256, 64);
+        let data = test_images::pattern(width, height);
+
+        let image = Image::new(&data, width, height).unwrap();
+        hist.add_image(&image);
+
+        assert!(hist.map.len() <= 1024);
+        assert!(hist.bits < 8);
+
+        let total_weight: f64 = hist.entries().map(|e| e.weight).sum();
+        assert_eq!(total_weight, (width * height) as f64);
+
+        // Entries keep the means rather than the reduced colors
+        let grid = (1 << (8 - hist.bits)) as f32;
+        assert!(hist.entries().any(|e| e.color[0] % grid != 0.0));
+    }
+
+    #[test]
+    fn test_histogram_add_image_weighted() {
+        let large = [255u8, 0, 0, 255].repeat(64 * 64);
+        let small = [0u8, 0, 255, 255].repeat(4 * 4);
+
+        let large = Image::new(&large, 64, 64).unwrap();
+        let small = Image::new(&small, 4, 4).unwrap();
+
+        let mut hist = Histogram::new();
+        assert!(hist.add_image_weighted(&large, -1.0, false).is_err());
+
+        hist.add_image_weighted(&large, 1.0, true).unwrap();
+        hist.add_image_weighted(&small, 1.0, true).unwrap();
+
+        for e in hist.entries() {
+            assert!((e.weight - 1.0).abs() < 1e-9);
+        }
+
+        hist.add_image_weighted(&small, 2.0, false).unwrap();
+
+        let blue = hist.entries().find(|e| e.color[2] == 255.0).unwrap();
+        assert!((blue.weight - 33.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_histogram_add_colors() {
+        let mut hist = Histogram::new();
+
+        let red = Color {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+        let transparent = Color {
+            r: 10,
+            g: 20,
+            b: 30,
+            a: 0,
+        };
+
+        assert!(hist.add_color(red, -1.0).is_err());
+        assert!(hist.add_colors(&[(red, 1.0), (red, f64::NAN)]).is_err());
+        assert!(hist.map.is_empty());
+
+        hist.add_colors(&[(red, 2.0), (transparent, 1.0), (red, 0.5)])
+            .unwrap();
+        hist.add_color(Color::default(), 1.0).unwrap();
+
+        let mut entries: Vec<_> = hist.entries().map(|e| (e.color, e.weight)).collect();
+        entries.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        // Transparent colors share the same entry
+        assert_eq!(entries, [([0.0; 4], 2.0), ([255.0, 0.0, 0.0, 255.0], 2.5)]);
+    }
+
+    #[test]
+    fn test_histogram_colors() {
+        let color = |r, g, b, a| Color { r, g, b, a };
+
+        let mut hist = Histogram::new();
+        assert!(hist.is_empty());
+        assert!(hist.dominant_colors(5).is_empty());
+
+        hist.add_colors(&[
+            (color(0, 0, 255, 255), 1.0),
+            (color(255, 0, 0, 255), 3.0),
+            (color(0, 255, 0, 255), 3.0),
+            (color(10, 20, 30, 0), 2.0),
+            (color(1, 2, 3, 0), 1.0),
+            (color(50, 50, 50, 255), 0.0),
+        ])
+        .unwrap();
+
+        // Transparent colors share an entry, and zero weights are skipped
+        assert_eq!(hist.len(), 4);
+
+        let mut colors: Vec<_> = hist.iter().collect();
+        colors.sort_by_key(|(c, _)| u32::from_le_bytes([c.r, c.g, c.b, c.a]));
+        assert_eq!(
+            colors,
+            [
+                (color(0, 0, 0, 0), 3.0),
+                (color(255, 0, 0, 255), 3.0),
+                (color(0, 255, 0, 255), 3.0),
+                (color(0, 0, 255, 255), 1.0),
+            ]
+        );
+
+        // The heaviest first, ties are ordered by color
+        assert_eq!(
+            hist.dominant_colors(3),
+            [
+                (color(0, 0, 0, 0), 3.0),
+                (color(255, 0, 0, 255), 3.0),
+                (color(0, 255, 0, 255), 3.0),
+            ]
+        );
+        assert_eq!(hist.dominant_colors(10).len(), 4);
+
+        // Reduced colors are the rounded means of the grouped colors
+        let mut hist = Histogram::new();
+        hist.set_bits_per_channel(4).unwrap();
+        hist.add_colors(&[(color(0, 0, 0, 255), 1.0), (color(3, 0, 0, 255), 2.0)])
+            .unwrap();
+
+        assert_eq!(hist.len(), 1);
+        assert_eq!(
+            hist.iter().collect::<Vec<_>>(),
+            [(color(2, 0, 0, 255), 3.0)]
+        );
+    }
+}
@@ -1,10 +1,105 @@
+use crate::colorspace;
 use crate::error::Error;
 
+/// Layout of the pixels in the image data
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel: red, green, blue, alpha
+    Rgba8,
+    /// 3 bytes per pixel: red, green, blue. Pixels are treated as fully opaque
+    Rgb8,
+    /// 4 bytes per pixel: blue, green, red, alpha
+    Bgra8,
+    /// 4 bytes per pixel: alpha, red, green, blue
+    Argb8,
+    /// 4 bytes per pixel: alpha, blue, green, red
+    Abgr8,
+    /// 8 bytes per pixel: red, green, blue, alpha as native-endian 16-bit values
+    Rgba16,
+    /// 1 byte per pixel: gray level. Pixels are treated as fully opaque
+    Gray8,
+    /// 2 bytes per pixel: gray level, alpha
+    GrayAlpha8,
+}
+
+impl PixelFormat {
+    /// Returns the number of bytes a single pixel takes
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            Self::Rgba8 | Self::Bgra8 | Self::Argb8 | Self::Abgr8 => 4,
+            Self::Rgb8 => 3,
+            Self::Rgba16 => 8,
+            Self::Gray8 => 1,
+            Self::GrayAlpha8 => 2,
+        }
+    }
+
+    /// Returns the number of bytes a single channel takes
+    #[inline(always)]
+    fn bytes_per_channel(&self) -> usize {
+        match self {
+            Self::Rgba16 => 2,
+            _ => 1,
+        }
+    }
+
+    /// Returns positions of red, green, blue, and alpha channels in a pixel.
+    /// Alpha position is `None` if the format has no alpha channel
+    #[inline(always)]
+    fn channel_offsets(&self) -> ([usize; 3], Option<usize>) {
+        match self {
+            Self::Rgba8 | Self::Rgba16 => ([0, 1, 2], Some(3)),
+            Self::Rgb8 => ([0, 1, 2], None),
+            Self::Bgra8 => ([2, 1, 0], Some(3)),
+            Self::Argb8 => ([1, 2, 3], Some(0)),
+            Self::Abgr8 => ([3, 2, 1], Some(0)),
+            Self::Gray8 => ([0, 0, 0], None),
+            Self::GrayAlpha8 => ([0, 0, 0], Some(1)),
+        }
+    }
+
+    /// Returns `true` if the format has a single color channel
+    #[inline(always)]
+    fn is_gray(&self) -> bool {
+        matches!(self, Self::Gray8 | Self::GrayAlpha8)
+    }
+
+    /// Writes RGBA `color` to `dst` in this format.
+    /// Colors are converted to their luma for grayscale formats
+    pub(crate) fn write_pixel(&self, mut color: [u8; 4], dst: &mut [u8]) {
+        if self.is_gray() {
+            let luma = 0.299 * color[0] as f32 + 0.587 * color[1] as f32 + 0.114 * color[2] as f32;
+            let luma = luma.round().clamp(0.0, 255.0) as u8;
+            color = [luma, luma, luma, color[3]];
+        }
+
+        let ([r, g, b], a) = self.channel_offsets();
+        let channels = [Some(r), Some(g), Some(b), a];
+
+        for (c, offset) in color.into_iter().zip(channels) {
+            let Some(offset) = offset else { continue };
+
+            if self.bytes_per_channel() == 2 {
+                let bytes = (c as u16 * 257).to_ne_bytes();
+                dst[offset * 2..offset * 2 + 2].copy_from_slice(&bytes);
+            } else {
+                dst[offset] = c;
+            }
+        }
+    }
+}
+
 /// Image reference containing pixel data and dimensions info
 pub struct Image<'data> {
     pub width: usize,
     pub height: usize,
+    /// The number of bytes between the starts of two consecutive rows
+    pub stride: usize,
+    pub format: PixelFormat,
+    /// Whether color channels are premultiplied by alpha
+    pub premultiplied: bool,
     pub data: &'data [u8],
+    importance_map: Option<&'data [u8]>,
 }
 
 impl<'data> Image<'data> {
@@ -13,14 +108,342 @@ impl<'data> Image<'data> {
     /// Returns [`Error::BufferTooSmall`] if the provided slice length is less
     /// than `width * height * 4`
     pub fn new(data: &'data [u8], width: usize, height: usize) -> Result<Self, Error> {
-        if data.len() < width * height * 4 {
-            return Err(Error::BufferTooSmall);
+        Self::with_format(data, width, height, PixelFormat::Rgba8)
+    }
+
+    /// Creates an [`Image`] from a slice of RGB pixels.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided slice length is less
+    /// than `width * height * 3`
+    pub fn new_rgb(data: &'data [u8], width: usize, height: usize) -> Result<Self, Error> {
+        Self::with_format(data, width, height, PixelFormat::Rgb8)
+    }
+
+    /// Creates an [`Image`] from a slice of pixels of the provided format.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the row size overflows.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided slice length is less
+    /// than `width * height * format.bytes_per_pixel()`
+    pub fn with_format(
+        data: &'data [u8],
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+    ) -> Result<Self, Error> {
+        let row_size = width
+            .checked_mul(format.bytes_per_pixel())
+            .ok_or(Error::ValueOutOfRange)?;
+
+        Self::with_stride(data, width, height, row_size, format)
+    }
+
+    /// Creates an [`Image`] from a slice of pixels of the provided format
+    /// where rows are `stride` bytes apart.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if `stride` is less than
+    /// `width * format.bytes_per_pixel()`.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided slice length is less
+    /// than `stride * (height - 1) + width * format.bytes_per_pixel()`
+    pub fn with_stride(
+        data: &'data [u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Result<Self, Error> {
+        let row_size = width
+            .checked_mul(format.bytes_per_pixel())
+            .ok_or(Error::ValueOutOfRange)?;
+
+        if stride < row_size {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        if height > 0 {
+            // A slice can't be larger than usize::MAX, so overflow means
+            // the buffer is too small
+            let size = stride
+                .checked_mul(height - 1)
+                .and_then(|s| s.checked_add(row_size))
+                .ok_or(Error::BufferTooSmall)?;
+
+            if data.len() < size {
+                return Err(Error::BufferTooSmall);
+            }
         }
 
         Ok(Self {
             data,
             width,
             height,
+            stride,
+            format,
+            premultiplied: false,
+            importance_map: None,
         })
     }
+
+    /// Returns the importance map of the image, `width * height` values.
+    /// See [`Image::set_importance_map`]
+    pub fn get_importance_map(&self) -> Option<&'data [u8]> {
+        self.importance_map
+    }
+
+    /// Sets the importance map of the image. The map contains one value per
+    /// pixel without any padding. Pixels with higher importance have bigger
+    /// weight in the histogram, so the palette represents them better.
+    /// Pixels with zero importance are ignored.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided slice length is less
+    /// than `width * height`
+    pub fn set_importance_map(&mut self, map: &'data [u8]) -> Result<(), Error> {
+        if map.len() < self.width * self.height {
+            return Err(Error::BufferTooSmall);
+        }
+
+        self.importance_map = Some(map);
+
+        Ok(())
+    }
+
+    /// Returns `true` if the image pixels can be read as 8-bit straight RGBA
+    /// without losing precision
+    pub(crate) fn is_exact_8bit(&self) -> bool {
+        self.format.bytes_per_channel() == 1 && !self.premultiplied
+    }
+
+    /// Reads the `y`th row of the image into `row` as RGBA pixels.
+    /// Should only be used if [`Image::is_exact_8bit`] returns `true`
+    pub(crate) fn read_row(&self, y: usize, row: &mut [[u8; 4]]) {
+        debug_assert!(self.is_exact_8bit());
+
+        let src = self.row_data(y);
+        let bpp = self.format.bytes_per_pixel();
+
+        match (
+            self.format.bytes_per_channel(),
+            self.format.channel_offsets(),
+        ) {
+            (1, ([r, g, b], Some(a))) => {
+                for (dst, pix) in row.iter_mut().zip(src.chunks_exact(bpp)) {
+                    *dst = [pix[r], pix[g], pix[b], pix[a]];
+                }
+            }
+            (1, ([r, g, b], None)) => {
+                for (dst, pix) in row.iter_mut().zip(src.chunks_exact(bpp)) {
+                    *dst = [pix[r], pix[g], pix[b], 255];
+                }
+            }
+            _ => unreachable!("only 8-bit formats can be read as 8-bit"),
+        }
+    }
+
+    /// Reads the `y`th row of the image into `row` as straight RGBA pixels
+    /// keeping the full precision of the channels. Channel values are scaled
+    /// to `0.0..=255.0` range
+    pub(crate) fn read_row_f32(&self, y: usize, row: &mut [[f32; 4]]) {
+        let src = self.row_data(y);
+        let bpp = self.format.bytes_per_pixel();
+
+        match (
+            self.format.bytes_per_channel(),
+            self.format.channel_offsets(),
+        ) {
+            (1, ([r, g, b], Some(a))) => {
+                for (dst, pix) in row.iter_mut().zip(src.chunks_exact(bpp)) {
+                    *dst = [pix[r] as f32, pix[g] as f32, pix[b] as f32, pix[a] as f32];
+                }
+            }
+            (1, ([r, g, b], None)) => {
+                for (dst, pix) in row.iter_mut().zip(src.chunks_exact(bpp)) {
+                    *dst = [pix[r] as f32, pix[g] as f32, pix[b] as f32, 255.0];
+                }
+            }
+            (_, ([r, g, b], Some(a))) => {
+                for (dst, pix) in row.iter_mut().zip(src.chunks_exact(bpp)) {
+                    *dst = [
+                        read_u16(pix, r) as f32 / 257.0,
+                        read_u16(pix, g) as f32 / 257.0,
+                        read_u16(pix, b) as f32 / 257.0,
+                        read_u16(pix, a) as f32 / 257.0,
+                    ];
+                }
+            }
+            (_, (_, None)) => unreachable!("16-bit formats always have alpha"),
+        }
+
+        if self.premultiplied {
+            for pix in row.iter_mut() {
+                *pix = colorspace::unpremultiply(*pix);
+            }
+        }
+    }
+
+    /// Returns the importance map values of the `y`th row if the image has
+    /// an importance map
+    #[inline(always)]
+    pub(crate) fn importance_row(&self, y: usize) -> Option<&[u8]> {
+        self.importance_map
+            .map(|map| &map[y * self.width..(y + 1) * self.width])
+    }
+
+    #[inline(always)]
+    fn row_data(&self, y: usize) -> &[u8] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width * self.format.bytes_per_pixel()]
+    }
+}
+
+#[inline(always)]
+fn read_u16(pix: &[u8], channel: usize) -> u16 {
+    u16::from_ne_bytes([pix[channel * 2], pix[channel * 2 + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_buffer_too_small() {
+        let data = [0u8; 12];
+
+        assert!(Image::new_rgb(&data, 2, 2).is_ok());
+        assert!(Image::new(&data, 2, 2).is_err());
+        assert!(Image::new_rgb(&data, 3, 2).is_err());
+    }
+
+    #[test]
+    fn test_image_stride() {
+        let data = [0u8; 22];
+
+        assert!(Image::with_stride(&data, 2, 2, 5, PixelFormat::Rgb8).is_err());
+        assert!(Image::with_stride(&data, 2, 3, 8, PixelFormat::Rgb8).is_ok());
+        assert!(Image::with_stride(&data, 3, 2, 11, PixelFormat::Rgba8).is_err());
+        assert!(Image::with_stride(&data, 3, 2, 12, PixelFormat::Rgba8).is_err());
+
+        // Overflowing sizes are rejected rather than wrapped
+        assert!(
+            Image::with_stride(&data, usize::MAX / 2, 1, usize::MAX, PixelFormat::Rgba8).is_err()
+        );
+        assert!(
+            Image::with_stride(&data, 1, usize::MAX, usize::MAX / 2, PixelFormat::Rgba8).is_err()
+        );
+        assert!(matches!(
+            Image::with_format(&[], usize::MAX, 1, PixelFormat::Rgba8),
+            Err(Error::ValueOutOfRange)
+        ));
+        assert!(Image::new_rgb(&[], usize::MAX / 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_image_read_row_rgb() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let image = Image::new_rgb(&data, 2, 2).unwrap();
+        let mut row = [[0u8; 4]; 2];
+
+        image.read_row(1, &mut row);
+
+        assert_eq!(row, [[7, 8, 9, 255], [10, 11, 12, 255]]);
+    }
+
+    #[test]
+    fn test_image_read_row_channel_order() {
+        let data = [1, 2, 3, 4];
+        let mut row = [[0u8; 4]; 1];
+
+        let cases = [
+            (PixelFormat::Rgba8, [1, 2, 3, 4]),
+            (PixelFormat::Bgra8, [3, 2, 1, 4]),
+            (PixelFormat::Argb8, [2, 3, 4, 1]),
+            (PixelFormat::Abgr8, [4, 3, 2, 1]),
+        ];
+
+        for (format, expected) in cases {
+            let image = Image::with_format(&data, 1, 1, format).unwrap();
+            image.read_row(0, &mut row);
+
+            assert_eq!(row, [expected], "{:?}", format);
+
+            let mut written = [0u8; 4];
+            format.write_pixel(expected, &mut written);
+
+            assert_eq!(written, data, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_image_read_row_gray() {
+        let data = [10, 20, 30, 40];
+        let mut row = [[0u8; 4]; 2];
+
+        let image = Image::with_format(&data, 2, 2, PixelFormat::Gray8).unwrap();
+        image.read_row(1, &mut row);
+        assert_eq!(row, [[30, 30, 30, 255], [40, 40, 40, 255]]);
+
+        let image = Image::with_format(&data, 2, 1, PixelFormat::GrayAlpha8).unwrap();
+        image.read_row(0, &mut row);
+        assert_eq!(row, [[10, 10, 10, 20], [30, 30, 30, 40]]);
+
+        let mut written = [0u8; 2];
+        PixelFormat::GrayAlpha8.write_pixel([30, 30, 30, 40], &mut written);
+        assert_eq!(written, [30, 40]);
+    }
+
+    #[test]
+    fn test_image_read_row_rgba16() {
+        let pixels: [u16; 4] = [0, 0x8080, 0xfffe, 0xffff];
+        let data: Vec<u8> = pixels.iter().flat_map(|p| p.to_ne_bytes()).collect();
+        let image = Image::with_format(&data, 1, 1, PixelFormat::Rgba16).unwrap();
+
+        let mut row = [[0f32; 4]; 1];
+        image.read_row_f32(0, &mut row);
+        assert_eq!(row, [[0.0, 128.0, 65534.0 / 257.0, 255.0]]);
+
+        let mut written = [0u8; 8];
+        PixelFormat::Rgba16.write_pixel([0, 128, 255, 255], &mut written);
+        assert_eq!(written[2..4], 0x8080u16.to_ne_bytes());
+        assert_eq!(written[6..8], 0xffffu16.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_image_read_row_premultiplied() {
+        let data = [0, 64, 128, 128, 10, 20, 30, 0, 10, 20, 30, 255];
+        let mut image = Image::new(&data, 3, 1).unwrap();
+        image.premultiplied = true;
+        let mut row = [[0f32; 4]; 3];
+
+        image.read_row_f32(0, &mut row);
+
+        assert_eq!(row[0], [0.0, 127.5, 255.0, 128.0]);
+        assert_eq!(row[1], [0.0; 4]);
+        assert_eq!(row[2], [10.0, 20.0, 30.0, 255.0]);
+    }
+
+    #[test]
+    fn test_image_read_row_padded() {
+        let data = [1, 2, 3, 0, 0, 4, 5, 6, 0, 0];
+        let image = Image::with_stride(&data, 1, 2, 5, PixelFormat::Rgb8).unwrap();
+        let mut row = [[0u8; 4]; 1];
+
+        image.read_row(1, &mut row);
+
+        assert_eq!(row, [[4, 5, 6, 255]]);
+    }
+
+    #[test]
+    fn test_image_importance_map() {
+        let data = [0u8; 16];
+        let map = [255, 0, 128];
+        let mut image = Image::new(&data, 2, 2).unwrap();
+
+        assert!(image.set_importance_map(&map).is_err());
+        assert_eq!(image.get_importance_map(), None);
+
+        let map = [255, 0, 128, 64];
+        image.set_importance_map(&map).unwrap();
+
+        assert_eq!(image.importance_row(1), Some(&map[2..4]));
+    }
 }
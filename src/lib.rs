@@ -3,6 +3,11 @@
 //! ### Quantizing an image
 //!
 //! ```
+//! # fn load_image() -> (&'static [u8], usize, usize) {
+//! #     (&[0; 16], 2, 2)
+//! # }
+//! # fn save_image(_: &quantizr::Palette, _: Vec<u8>, _: usize, _: usize) {}
+//! #
 //! let (bytes, width, height) = load_image();
 //!
 //! let image = quantizr::Image::new(bytes, width, height)?;
@@ -19,13 +24,74 @@
 //! let palette = result.get_palette();
 //!
 //! save_image(palette, indexes, width, height);
+//! # Ok::<(), quantizr::Error>(())
 //! ```
 //!
 //! See `example/` directory for complete example code.
 //!
+//! ### Quantizing an RGB image
+//!
+//! RGB images can be used without converting them to RGBA. Their pixels are
+//! treated as fully opaque.
+//!
+//! ```
+//! # fn load_rgb_image() -> (&'static [u8], usize, usize) {
+//! #     (&[0; 12], 2, 2)
+//! # }
+//! #
+//! let (bytes, width, height) = load_rgb_image();
+//!
+//! let image = quantizr::Image::new_rgb(bytes, width, height)?;
+//! # Ok::<(), quantizr::Error>(())
+//! ```
+//!
+//! ### Quantizing a premultiplied image
+//!
+//! ```
+//! # fn load_premultiplied_image() -> (&'static [u8], usize, usize) {
+//! #     (&[0; 16], 2, 2)
+//! # }
+//! #
+//! let (bytes, width, height) = load_premultiplied_image();
+//!
+//! let mut image = quantizr::Image::new(bytes, width, height)?;
+//! image.premultiplied = true;
+//!
+//! let mut opts = quantizr::Options::default();
+//! // Average and compare colors in premultiplied space
+//! opts.set_premultiplied_alpha(true);
+//! // Get the palette with premultiplied colors
+//! opts.set_premultiplied_palette(true);
+//!
+//! let result = quantizr::QuantizeResult::quantize(&image, &opts);
+//! # Ok::<(), quantizr::Error>(())
+//! ```
+//!
+//! ### Quantizing in linear light or perceptual color space
+//!
+//! ```
+//! # let image = quantizr::Image::new(&[0; 16], 2, 2)?;
+//! let mut opts = quantizr::Options::default();
+//! opts.set_linear_light(quantizr::LinearLight::Srgb)?;
+//! // Or
+//! opts.set_color_space(quantizr::ColorSpace::Oklab);
+//!
+//! let result = quantizr::QuantizeResult::quantize(&image, &opts);
+//! # Ok::<(), quantizr::Error>(())
+//! ```
+//!
 //! ### Quantizing multiple image into a single palette
 //!
 //! ```
+//! # fn load_image1() -> (&'static [u8], usize, usize) {
+//! #     (&[0; 16], 2, 2)
+//! # }
+//! # fn load_image2() -> (&'static [u8], usize, usize) {
+//! #     (&[0; 16], 2, 2)
+//! # }
+//! # fn save_image1(_: &quantizr::Palette, _: Vec<u8>, _: usize, _: usize) {}
+//! # fn save_image2(_: &quantizr::Palette, _: Vec<u8>, _: usize, _: usize) {}
+//! #
 //! let mut hist = quantizr::Histogram::new();
 //!
 //! let (bytes1, width1, height1) = load_image1();
@@ -42,33 +108,50 @@
 //! let mut result = quantizr::QuantizeResult::quantize_histogram(&hist, &opts);
 //! result.set_dithering_level(1.0)?;
 //!
-//! let mut indexes1 = vec![0u8; width * height];
+//! let mut indexes1 = vec![0u8; width1 * height1];
 //! result.remap_image(&image1, indexes1.as_mut_slice())?;
 //!
-//! let mut indexes2 = vec![0u8; width * height];
+//! let mut indexes2 = vec![0u8; width2 * height2];
 //! result.remap_image(&image2, indexes2.as_mut_slice())?;
 //!
 //! let palette = result.get_palette();
 //!
 //! save_image1(palette, indexes1, width1, height1);
 //! save_image2(palette, indexes2, width2, height2);
+//! # Ok::<(), quantizr::Error>(())
 //! ```
 
 mod cluster;
 mod colormap;
+mod colorspace;
+mod contrast;
 mod error;
+mod gray;
 mod histogram;
 mod image;
+mod moments;
+mod neuquant;
+mod octree;
 mod options;
 mod ord_float;
 mod palette;
 mod quantize;
+mod spatial;
+#[cfg(test)]
+mod test_images;
 mod vpsearch;
+mod wu;
 
 pub use error::Error;
 pub use histogram::Histogram;
 pub use image::Image;
+pub use image::PixelFormat;
+pub use octree::Octree;
+pub use options::Algorithm;
+pub use options::ColorSpace;
+pub use options::LinearLight;
 pub use options::Options;
+pub use options::SplitStrategy;
 pub use palette::Color;
 pub use palette::Palette;
 pub use quantize::QuantizeResult;
@@ -0,0 +1,277 @@
+use crate::cluster::Cluster;
+use crate::colorspace::ColorEntry;
+use crate::ord_float::OrdFloat32;
+
+/// Sums of the weights, colors, and squared colors of a box
+pub(crate) struct BoxStats {
+    pub weight: f64,
+    pub color: [f64; 4],
+    pub sq: f64,
+}
+
+impl BoxStats {
+    pub(crate) fn variance(&self) -> f64 {
+        if self.weight <= 0.0 {
+            return 0.0;
+        }
+
+        self.sq - norm_sq(&self.color) / self.weight
+    }
+
+    /// The part of the variance formula that depends on the split
+    pub(crate) fn gain(&self) -> f64 {
+        if self.weight <= 0.0 {
+            return 0.0;
+        }
+
+        norm_sq(&self.color) / self.weight
+    }
+}
+
+/// Box of an N-dimensional grid of bins
+#[derive(Clone, Copy)]
+struct MomentBox<const N: usize> {
+    // Lower bounds are exclusive, upper bounds are inclusive
+    lo: [usize; N],
+    hi: [usize; N],
+    variance: f32,
+}
+
+/// Cumulative moments of an N-dimensional grid of bins. Every dimension has
+/// a leading zero layer for prefix sums, so the statistics of any box are
+/// computed in constant time regardless of the number of colors
+struct Moments<const N: usize> {
+    dims: [usize; N],
+    strides: [usize; N],
+    weight: Vec<f64>,
+    color: Vec<[f64; 4]>,
+    sq: Vec<f64>,
+}
+
+impl<const N: usize> Moments<N> {
+    fn new(entries: &[ColorEntry], bins: &[[usize; N]], sizes: [usize; N]) -> Self {
+        let dims = sizes.map(|s| s + 1);
+        let strides = strides(&dims);
+        let size = dims.iter().product();
+
+        let mut m = Self {
+            dims,
+            strides,
+            weight: vec![0.0; size],
+            color: vec![[0.0; 4]; size],
+            sq: vec![0.0; size],
+        };
+
+        for (e, bin) in entries.iter().zip(bins) {
+            let ind = m.index(&bin.map(|b| b + 1));
+            let weight = e.weight as f64;
+
+            m.weight[ind] += weight;
+            for (mc, c) in m.color[ind].iter_mut().zip(e.color) {
+                *mc += c as f64 * weight;
+            }
+            m.sq[ind] += norm_sq(&e.color.map(|c| c as f64)) * weight;
+        }
+
+        // Turn the grid into prefix sums one dimension at a time
+        for axis in 0..N {
+            let stride = m.strides[axis];
+
+            for ind in 0..size {
+                if (ind / stride).is_multiple_of(m.dims[axis]) {
+                    continue;
+                }
+
+                let prev = ind - stride;
+
+                m.weight[ind] += m.weight[prev];
+                m.sq[ind] += m.sq[prev];
+                for c in 0..4 {
+                    m.color[ind][c] += m.color[prev][c];
+                }
+            }
+        }
+
+        m
+    }
+
+    #[inline(always)]
+    fn index(&self, coords: &[usize; N]) -> usize {
+        coords.iter().zip(self.strides).map(|(c, s)| c * s).sum()
+    }
+
+    fn stats(&self, b: &MomentBox<N>) -> BoxStats {
+        let mut stats = BoxStats {
+            weight: 0.0,
+            color: [0.0; 4],
+            sq: 0.0,
+        };
+
+        // Inclusion-exclusion over the corners of the box
+        for corner in 0..1u32 << N {
+            let mut coords = b.hi;
+            for (c, coord) in coords.iter_mut().enumerate() {
+                if corner & (1 << c) != 0 {
+                    *coord = b.lo[c];
+                }
+            }
+
+            let ind = self.index(&coords);
+            let sign = if corner.count_ones() % 2 == 0 {
+                1.0
+            } else {
+                -1.0
+            };
+
+            stats.weight += self.weight[ind] * sign;
+            stats.sq += self.sq[ind] * sign;
+            for c in 0..4 {
+                stats.color[c] += self.color[ind][c] * sign;
+            }
+        }
+
+        stats
+    }
+
+    /// Finds the cut of the box that minimizes the total variance of the
+    /// resulting boxes
+    fn best_split(&self, b: &MomentBox<N>) -> Option<(MomentBox<N>, MomentBox<N>)> {
+        let mut best: Option<(f64, MomentBox<N>, MomentBox<N>)> = None;
+
+        for axis in 0..N {
+            for pos in b.lo[axis] + 1..b.hi[axis] {
+                let mut lo = *b;
+                let mut hi = *b;
+                lo.hi[axis] = pos;
+                hi.lo[axis] = pos;
+
+                let (slo, shi) = (self.stats(&lo), self.stats(&hi));
+                if slo.weight <= 0.0 || shi.weight <= 0.0 {
+                    continue;
+                }
+
+                let gain = slo.gain() + shi.gain();
+                if best.as_ref().is_none_or(|(g, _, _)| gain > *g) {
+                    best = Some((gain, lo, hi));
+                }
+            }
+        }
+
+        best.map(|(_, mut lo, mut hi)| {
+            lo.variance = self.stats(&lo).variance() as f32;
+            hi.variance = self.stats(&hi).variance() as f32;
+            (lo, hi)
+        })
+    }
+}
+
+/// Splits colors into clusters by recursively cutting boxes of
+/// an N-dimensional grid of bins. `bins` contains the bin of every entry,
+/// and `sizes` contains the number of bins of every dimension.
+/// The box with the largest variance is cut at the point that minimizes
+/// the total variance.
+///
+/// If `target_error` is greater than zero, splitting stops once the mean
+/// squared error drops to it
+pub(crate) fn split_boxes<'clust, const N: usize>(
+    entries: &'clust [ColorEntry],
+    bins: &[[usize; N]],
+    sizes: [usize; N],
+    max_colors: usize,
+    target_error: f32,
+) -> Vec<Cluster<'clust>> {
+    let moments = Moments::new(entries, bins, sizes);
+
+    let mut root = MomentBox {
+        lo: [0; N],
+        hi: sizes,
+        variance: 0.0,
+    };
+    let root_stats = moments.stats(&root);
+    root.variance = root_stats.variance() as f32;
+
+    let total_weight = root_stats.weight as f32;
+    let mut total_error = root.variance;
+
+    let mut boxes = Vec::with_capacity(max_colors);
+    boxes.push(root);
+
+    while boxes.len() < max_colors {
+        if target_error > 0.0 && total_error <= target_error * total_weight {
+            break;
+        }
+
+        let to_split_opt = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.variance > 0.0)
+            .max_by_key(|(_, b)| OrdFloat32::from(b.variance))
+            .map(|(i, _)| i);
+
+        let Some(to_split) = to_split_opt else {
+            break;
+        };
+
+        match moments.best_split(&boxes[to_split]) {
+            Some((lo, hi)) => {
+                total_error += lo.variance + hi.variance - boxes[to_split].variance;
+                boxes[to_split] = lo;
+                boxes.push(hi);
+            }
+            None => boxes[to_split].variance = 0.0,
+        }
+    }
+
+    // Paint the grid with box indexes to find the box of every entry
+    let label_strides = strides(&sizes);
+    let label_index =
+        |bin: &[usize; N]| -> usize { bin.iter().zip(label_strides).map(|(b, s)| b * s).sum() };
+
+    let mut labels = vec![0u8; sizes.iter().product()];
+    for (i, b) in boxes.iter().enumerate() {
+        // Walk the rows of the box along the last dimension
+        let mut coords = b.lo;
+
+        'rows: loop {
+            let start = label_index(&coords);
+            labels[start..start + b.hi[N - 1] - b.lo[N - 1]].fill(i as u8);
+
+            for axis in (0..N - 1).rev() {
+                coords[axis] += 1;
+                if coords[axis] < b.hi[axis] {
+                    continue 'rows;
+                }
+                coords[axis] = b.lo[axis];
+            }
+
+            break;
+        }
+    }
+
+    let mut box_entries: Vec<Vec<&ColorEntry>> = vec![Vec::new(); boxes.len()];
+    for (e, bin) in entries.iter().zip(bins) {
+        box_entries[labels[label_index(bin)] as usize].push(e);
+    }
+
+    box_entries
+        .into_iter()
+        .filter(|e| !e.is_empty())
+        .map(Cluster::new)
+        .collect()
+}
+
+/// Returns the strides of a row-major grid
+fn strides<const N: usize>(dims: &[usize; N]) -> [usize; N] {
+    let mut strides = [1usize; N];
+
+    for axis in (0..N - 1).rev() {
+        strides[axis] = strides[axis + 1] * dims[axis + 1];
+    }
+
+    strides
+}
+
+#[inline(always)]
+pub(crate) fn norm_sq<T: Into<f64> + Copy>(c: &[T; 4]) -> f64 {
+    c.iter().map(|&v| v.into() * v.into()).sum()
+}
@@ -0,0 +1,257 @@
+use crate::cluster::Cluster;
+use crate::colorspace::ColorEntry;
+use crate::vpsearch;
+
+// Number of learning cycles. Learning rate and radius decrease every cycle
+const CYCLES: usize = 100;
+// Frequencies of neurons are updated with this rate
+const BETA: f32 = 1.0 / 1024.0;
+// Biases are frequencies scaled by this factor
+const GAMMA: f32 = 1024.0;
+const BETA_GAMMA: f32 = BETA * GAMMA;
+// Learning rate decreases by 1/ALPHA_DEC every cycle
+const ALPHA_DEC: f32 = 30.0;
+// Radius decreases by 1/RADIUS_DEC every cycle
+const RADIUS_DEC: f32 = 30.0;
+// Samples per neuron are bounded so the learning takes a reasonable time
+const MIN_SAMPLES_PER_NEURON: usize = 100;
+const MAX_SAMPLES: usize = 1 << 20;
+
+struct Network {
+    neurons: Vec<[f32; 4]>,
+    freq: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl Network {
+    /// Creates a network with neurons evenly spread along the diagonal of
+    /// the colors bounding box
+    fn new(size: usize, entries: &[ColorEntry]) -> Self {
+        let mut min = [f32::MAX; 4];
+        let mut max = [f32::MIN; 4];
+
+        for e in entries {
+            for c in 0..4 {
+                min[c] = min[c].min(e.color[c]);
+                max[c] = max[c].max(e.color[c]);
+            }
+        }
+
+        let neurons = (0..size)
+            .map(|i| {
+                let k = i as f32 / (size - 1).max(1) as f32;
+                [0, 1, 2, 3].map(|c| min[c] + (max[c] - min[c]) * k)
+            })
+            .collect();
+
+        Self {
+            neurons,
+            freq: vec![1.0 / size as f32; size],
+            bias: vec![0.0; size],
+        }
+    }
+
+    /// Finds the neuron closest to the color taking the bias into account,
+    /// and updates the frequencies and biases so rarely winning neurons get
+    /// a chance to learn
+    fn contest(&mut self, color: &[f32; 4]) -> usize {
+        let mut best_dist = f32::MAX;
+        let mut best_bias_dist = f32::MAX;
+        let mut best = 0;
+        let mut best_bias = 0;
+
+        for (i, n) in self.neurons.iter().enumerate() {
+            let dist: f32 = n.iter().zip(color).map(|(nc, c)| (nc - c).abs()).sum();
+
+            if dist < best_dist {
+                best_dist = dist;
+                best = i;
+            }
+
+            let bias_dist = dist - self.bias[i];
+            if bias_dist < best_bias_dist {
+                best_bias_dist = bias_dist;
+                best_bias = i;
+            }
+
+            let beta_freq = self.freq[i] * BETA;
+            self.freq[i] -= beta_freq;
+            self.bias[i] += beta_freq * GAMMA;
+        }
+
+        self.freq[best] += BETA;
+        self.bias[best] -= BETA_GAMMA;
+
+        best_bias
+    }
+
+    /// Moves the neuron and its neighbours towards the color
+    fn alter(&mut self, ind: usize, radius: usize, alpha: f32, color: &[f32; 4]) {
+        let lo = ind.saturating_sub(radius);
+        let hi = (ind + radius).min(self.neurons.len() - 1);
+        let rad_sq = ((radius + 1) * (radius + 1)) as f32;
+
+        for j in lo..=hi {
+            let d = j.abs_diff(ind) as f32;
+            let a = alpha * (1.0 - d * d / rad_sq);
+
+            for (nc, c) in self.neurons[j].iter_mut().zip(color) {
+                *nc += (c - *nc) * a;
+            }
+        }
+    }
+}
+
+/// Splits colors into clusters using Anthony Dekker's NeuQuant algorithm.
+/// A self-organizing network of `max_colors` neurons learns from colors
+/// sampled proportionally to their weights, then the colors are clustered
+/// around the nearest neurons.
+///
+/// The network can't learn fewer colors on its own, so if `target_error` is
+/// greater than zero, the closest clusters are merged while the mean squared
+/// error stays within it
+pub(crate) fn split_neuquant(
+    entries: &[ColorEntry],
+    max_colors: usize,
+    target_error: f32,
+) -> Vec<Cluster<'_>> {
+    let mut network = Network::new(max_colors, entries);
+
+    let mut cumulative = Vec::with_capacity(entries.len());
+    let mut total_weight = 0f64;
+    for e in entries {
+        total_weight += e.weight as f64;
+        cumulative.push(total_weight);
+    }
+
+    let samples = (total_weight as usize)
+        .min(MAX_SAMPLES)
+        .max(max_colors * MIN_SAMPLES_PER_NEURON);
+    let samples_per_cycle = samples.div_ceil(CYCLES);
+
+    let mut alpha = 1.0f32;
+    let mut radius = (max_colors / 8) as f32;
+
+    // Simple LCG so the result is reproducible
+    let mut seed = 0x2545f491u64;
+
+    for i in 0..samples {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let r = (seed >> 11) as f64 / (1u64 << 53) as f64 * total_weight;
+        let ind = cumulative
+            .partition_point(|&w| w <= r)
+            .min(entries.len() - 1);
+
+        let color = &entries[ind].color;
+        let winner = network.contest(color);
+
+        let rad = if radius <= 1.0 { 0 } else { radius as usize };
+        network.alter(winner, rad, alpha, color);
+
+        if (i + 1) % samples_per_cycle == 0 {
+            alpha -= alpha / ALPHA_DEC;
+            radius -= radius / RADIUS_DEC;
+        }
+    }
+
+    // Cluster the colors around the nearest neurons
+    let weights = vec![1f32; max_colors];
+    let tree = vpsearch::SearchTree::new(&network.neurons, &weights);
+
+    let mut neuron_entries: Vec<Vec<&ColorEntry>> = vec![Vec::new(); max_colors];
+    for e in entries {
+        let (ind, _, _) = tree.find_nearest(&e.color);
+        neuron_entries[ind as usize].push(e);
+    }
+
+    let mut clusters: Vec<Cluster> = neuron_entries
+        .into_iter()
+        .filter(|e| !e.is_empty())
+        .map(Cluster::new)
+        .collect();
+
+    if target_error > 0.0 {
+        merge_clusters(&mut clusters, target_error);
+    }
+
+    clusters
+}
+
+/// Merges pairs of clusters that increase the error the least until
+/// the next merge would make the mean squared error exceed `target_error`
+fn merge_clusters(clusters: &mut Vec<Cluster>, target_error: f32) {
+    let total_weight: f64 = clusters.iter().map(|c| c.weight as f64).sum();
+    let mut total_error: f64 = clusters.iter().map(|c| c.error as f64).sum();
+    let max_error = target_error as f64 * total_weight;
+
+    while clusters.len() > 1 {
+        let mut best = (f64::MAX, 0, 0);
+
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                // Increase of the error caused by merging two clusters
+                let (a, b) = (&clusters[i], &clusters[j]);
+                let (wa, wb) = (a.weight as f64, b.weight as f64);
+                let dist: f64 = a
+                    .mean
+                    .iter()
+                    .zip(b.mean)
+                    .map(|(ma, mb)| ((ma - mb) as f64).powi(2))
+                    .sum();
+                let cost = wa * wb / (wa + wb) * dist;
+
+                if cost < best.0 {
+                    best = (cost, i, j);
+                }
+            }
+        }
+
+        let (cost, i, j) = best;
+        if total_error + cost > max_error {
+            break;
+        }
+
+        let merged = clusters.swap_remove(j);
+        let mut entries = std::mem::take(&mut clusters[i].entries);
+        entries.extend(merged.entries);
+        clusters[i] = Cluster::new(entries);
+
+        total_error += cost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_neuquant_target_error() {
+        let entries: Vec<ColorEntry> = (0..4096)
+            .map(|i| ColorEntry {
+                color: [
+                    (i % 64 * 4) as f32,
+                    (i / 64 * 4) as f32,
+                    (i * 7 % 256) as f32,
+                    255.0,
+                ],
+                weight: 1.0,
+            })
+            .collect();
+
+        let mse = |clusters: &[Cluster]| -> f32 {
+            clusters.iter().map(|c| c.error).sum::<f32>() / entries.len() as f32
+        };
+
+        let clusters = split_neuquant(&entries, 64, 0.0);
+        assert!(clusters.len() > 56);
+
+        // Twice the error of all the trained colors can be met with fewer
+        let target_error = mse(&clusters) * 2.0;
+        let clusters = split_neuquant(&entries, 64, target_error);
+
+        assert!(clusters.len() < 56, "{}", clusters.len());
+        assert!(mse(&clusters) <= target_error * 1.001);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::error::Error;
+use crate::histogram::HistogramEntry;
+use crate::image::Image;
+
+// Every level splits colors by one bit of each of the 4 channels
+const MAX_DEPTH: usize = 8;
+const CHILDREN: usize = 16;
+const DEFAULT_MAX_NODES: usize = 65536;
+const MIN_MAX_NODES: usize = 1024;
+
+#[derive(Clone, Copy, Default)]
+struct Node {
+    // Indexes of the children nodes. 0 means there's no child since 0 is
+    // the index of the root
+    children: [u32; CHILDREN],
+    leaf: bool,
+    // Total weight of the colors in the subtree
+    weight: f64,
+    // Weighted sum of the colors. Only leaves keep it
+    color: [f64; 4],
+}
+
+/// Octree of colors. Unlike [`Histogram`](crate::Histogram), the octree
+/// never contains more than the provided number of nodes: when the limit is
+/// reached, the least important similar colors are merged. This allows
+/// building a palette for huge images with bounded memory.
+///
+/// Since merged colors are quantized as their means, the error and
+/// the quality of the result don't include the difference between the merged
+/// colors
+pub struct Octree {
+    nodes: Vec<Node>,
+    free: Vec<u32>,
+    // Nodes that have children, by level
+    reducible: Vec<Vec<u32>>,
+    // Nodes of this level are created as leaves
+    leaf_level: usize,
+    max_nodes: usize,
+    nodes_count: usize,
+    // Number of added pixels regardless of their weights
+    pub(crate) pixels: usize,
+}
+
+impl Default for Octree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Octree {
+    /// Creates new empty [`Octree`] limited to 65536 nodes
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+            free: Vec::new(),
+            reducible: vec![Vec::new(); MAX_DEPTH],
+            leaf_level: MAX_DEPTH,
+            max_nodes: DEFAULT_MAX_NODES,
+            nodes_count: 1,
+            pixels: 0,
+        }
+    }
+
+    /// Creates new empty [`Octree`] limited to `max_nodes` nodes.
+    /// Every node takes about 100 bytes.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if `max_nodes` is less than 1024
+    pub fn with_max_nodes(max_nodes: usize) -> Result<Self, Error> {
+        if max_nodes < MIN_MAX_NODES {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        let mut octree = Self::new();
+        octree.max_nodes = max_nodes;
+
+        Ok(octree)
+    }
+
+    /// Adds colors from [`Image`] to the octree.
+    /// If the image has an importance map, colors are weighted by it
+    pub fn add_image(&mut self, image: &Image) {
+        let mut row = vec![[0f32; 4]; image.width];
+
+        for y in 0..image.height {
+            image.read_row_f32(y, &mut row);
+            let importance = image.importance_row(y);
+
+            for (x, pix) in row.iter().enumerate() {
+                let weight = importance.map_or(1.0, |imp| imp[x] as f64 / 255.0);
+                if weight <= 0.0 {
+                    continue;
+                }
+
+                self.add_color(pix, weight);
+                self.pixels += 1;
+            }
+        }
+    }
+
+    fn add_color(&mut self, pix: &[f32; 4], weight: f64) {
+        let mut key = pix.map(|c| c.round().clamp(0.0, 255.0) as u8);
+        let mut color = *pix;
+
+        if key[3] == 0 {
+            key = [0; 4];
+            color = [0.0; 4];
+        }
+
+        let mut ind = 0usize;
+
+        for level in 0..=MAX_DEPTH {
+            let node = &mut self.nodes[ind];
+            node.weight += weight;
+
+            if node.leaf {
+                for (nc, c) in node.color.iter_mut().zip(color) {
+                    *nc += c as f64 * weight;
+                }
+                break;
+            }
+
+            let child = child_index(&key, level);
+
+            if node.children[child] == 0 {
+                let new_ind = self.new_node(level + 1);
+                self.nodes[ind].children[child] = new_ind;
+            }
+
+            ind = self.nodes[ind].children[child] as usize;
+        }
+
+        while self.nodes_count > self.max_nodes {
+            if !self.reduce() {
+                break;
+            }
+        }
+    }
+
+    fn new_node(&mut self, level: usize) -> u32 {
+        let node = Node {
+            leaf: level >= self.leaf_level,
+            ..Default::default()
+        };
+
+        let ind = match self.free.pop() {
+            Some(ind) => {
+                self.nodes[ind as usize] = node;
+                ind
+            }
+            None => {
+                self.nodes.push(node);
+                (self.nodes.len() - 1) as u32
+            }
+        };
+
+        if !node.leaf {
+            self.reducible[level].push(ind);
+        }
+
+        self.nodes_count += 1;
+
+        ind
+    }
+
+    /// Merges children of the lightest node of the deepest level into it.
+    /// Returns `false` if there's nothing to merge
+    fn reduce(&mut self) -> bool {
+        let Some(level) = (1..MAX_DEPTH)
+            .rev()
+            .find(|&l| !self.reducible[l].is_empty())
+        else {
+            return false;
+        };
+
+        let nodes = &self.nodes;
+        let (pos, _) = self.reducible[level]
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                nodes[**a as usize]
+                    .weight
+                    .total_cmp(&nodes[**b as usize].weight)
+            })
+            .unwrap();
+        let ind = self.reducible[level].swap_remove(pos) as usize;
+
+        let mut color = [0f64; 4];
+
+        for child in self.nodes[ind].children {
+            if child == 0 {
+                continue;
+            }
+
+            for (c, cc) in color.iter_mut().zip(self.nodes[child as usize].color) {
+                *c += cc;
+            }
+
+            self.free.push(child);
+            self.nodes_count -= 1;
+        }
+
+        let node = &mut self.nodes[ind];
+        node.children = [0; CHILDREN];
+        node.leaf = true;
+        node.color = color;
+
+        // The tree shouldn't grow deeper than the merged nodes
+        self.leaf_level = level;
+
+        true
+    }
+
+    /// Returns the mean colors of the leaves
+    pub(crate) fn leaves(&self) -> Vec<HistogramEntry> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![0usize];
+
+        while let Some(ind) = stack.pop() {
+            let node = &self.nodes[ind];
+
+            if node.leaf {
+                if node.weight > 0.0 {
+                    leaves.push(HistogramEntry {
+                        color: node.color.map(|c| (c / node.weight) as f32),
+                        weight: node.weight,
+                    });
+                }
+                continue;
+            }
+
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|&&c| c != 0)
+                    .map(|&c| c as usize),
+            );
+        }
+
+        leaves
+    }
+}
+
+#[inline(always)]
+fn child_index(color: &[u8; 4], level: usize) -> usize {
+    let shift = 7 - level;
+
+    (((color[0] >> shift) & 1)
+        | (((color[1] >> shift) & 1) << 1)
+        | (((color[2] >> shift) & 1) << 2)
+        | (((color[3] >> shift) & 1) << 3)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_images;
+
+    #[test]
+    fn test_octree_max_nodes() {
+        assert!(Octree::with_max_nodes(100).is_err());
+
+        let (width, height) = (256, 64);
+        let data = test_images::pattern(width, height);
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let mut octree = Octree::with_max_nodes(1024).unwrap();
+        octree.add_image(&image);
+
+        assert!(octree.nodes_count <= 1024);
+
+        let leaves = octree.leaves();
+        let total_weight: f64 = leaves.iter().map(|l| l.weight).sum();
+
+        assert!(leaves.len() >= 256);
+        assert_eq!(total_weight, (width * height) as f64);
+    }
+}
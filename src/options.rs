@@ -1,13 +1,98 @@
 use crate::error::Error;
+use crate::palette::Color;
+
+/// Transfer function used to convert colors to linear light
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinearLight {
+    /// Colors are quantized as is
+    Disabled,
+    /// Colors are converted using the exact sRGB transfer function
+    Srgb,
+    /// Colors are converted using a power function with the provided gamma
+    Gamma(f32),
+}
+
+/// Color space in which colors are clustered and compared
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorSpace {
+    /// RGB color space
+    Rgb,
+    /// Oklab perceptual color space. Distances in Oklab are closer to the
+    /// perceived difference between colors
+    Oklab,
+}
+
+/// Algorithm used to split colors into clusters
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    /// Clusters of colors are split recursively according to
+    /// the [`SplitStrategy`]
+    MedianCut,
+    /// Xiaolin Wu's algorithm. Colors are binned into a grid of cumulative
+    /// moments, so splitting doesn't depend on the number of colors. Faster
+    /// than [`Algorithm::MedianCut`] on images with many colors, but colors
+    /// that fall into the same bin can't be separated
+    Wu,
+    /// Anthony Dekker's NeuQuant neural network. Gives smooth palettes that
+    /// work well for photos, but is several times slower than the other
+    /// algorithms. The network always learns `max_colors` colors, and then
+    /// the closest ones are merged to meet the target error or the maximum
+    /// quality, so they don't make it faster
+    NeuQuant,
+}
+
+/// Strategy of splitting colors into clusters
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SplitStrategy {
+    /// Clusters are cut at the mean of their widest channel. Bigger clusters
+    /// are split first
+    MedianCut,
+    /// Clusters are cut along the channel and at the point that minimize
+    /// the total weighted variance. Clusters with bigger variance are split
+    /// first. Slower than [`SplitStrategy::MedianCut`]
+    Variance,
+}
 
 /// Quantization options
 pub struct Options {
     max_colors: i32,
+    premultiplied_alpha: bool,
+    premultiplied_palette: bool,
+    linear_light: LinearLight,
+    color_space: ColorSpace,
+    channel_weights: [f32; 4],
+    contrast_maps: bool,
+    fixed_colors: Vec<Color>,
+    min_quality: u8,
+    max_quality: u8,
+    target_error: f32,
+    speed: u8,
+    split_strategy: SplitStrategy,
+    algorithm: Algorithm,
+    histogram_bits: u8,
+    max_histogram_entries: usize,
 }
 
 impl Default for Options {
     fn default() -> Self {
-        Self { max_colors: 256 }
+        Self {
+            max_colors: 256,
+            premultiplied_alpha: false,
+            premultiplied_palette: false,
+            linear_light: LinearLight::Disabled,
+            color_space: ColorSpace::Rgb,
+            channel_weights: [1.0; 4],
+            contrast_maps: false,
+            fixed_colors: Vec::new(),
+            min_quality: 0,
+            max_quality: 100,
+            target_error: 0.0,
+            speed: 4,
+            split_strategy: SplitStrategy::MedianCut,
+            algorithm: Algorithm::MedianCut,
+            histogram_bits: 8,
+            max_histogram_entries: 0,
+        }
     }
 }
 
@@ -19,9 +104,10 @@ impl Options {
     /// Sets the maximum number of colors in the resultant palette.
     ///
     /// Returns [`Error::ValueOutOfRange`] if the provided number is greater
-    /// than 256 or less than 2
+    /// than 256 or less than 2, or if it doesn't leave room for at least one
+    /// color besides the fixed ones
     pub fn set_max_colors(&mut self, colors: i32) -> Result<(), Error> {
-        if !(2..=256).contains(&colors) {
+        if !(2..=256).contains(&colors) || self.fixed_colors.len() >= colors as usize {
             return Err(Error::ValueOutOfRange);
         }
 
@@ -29,4 +115,274 @@ impl Options {
 
         Ok(())
     }
+
+    pub fn get_premultiplied_alpha(&self) -> bool {
+        self.premultiplied_alpha
+    }
+
+    /// Sets whether colors should be averaged and compared in premultiplied
+    /// alpha space. This way semi-transparent colors affect the palette
+    /// proportionally to their opacity. Disabled by default
+    pub fn set_premultiplied_alpha(&mut self, premultiplied: bool) {
+        self.premultiplied_alpha = premultiplied;
+    }
+
+    pub fn get_premultiplied_palette(&self) -> bool {
+        self.premultiplied_palette
+    }
+
+    /// Sets whether the resultant [`Palette`](crate::Palette) should contain
+    /// premultiplied colors. Disabled by default
+    pub fn set_premultiplied_palette(&mut self, premultiplied: bool) {
+        self.premultiplied_palette = premultiplied;
+    }
+
+    pub fn get_linear_light(&self) -> LinearLight {
+        self.linear_light
+    }
+
+    /// Sets whether colors should be averaged, compared, and dithered in
+    /// linear light. Averaging sRGB-encoded colors makes them darker than
+    /// they should be, so quantizing in linear light gives more accurate
+    /// colors and dithered gradients. Colors are converted back when building
+    /// the palette. Disabled by default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided gamma is greater
+    /// than 10.0 or less than 0.1
+    pub fn set_linear_light(&mut self, linear_light: LinearLight) -> Result<(), Error> {
+        if let LinearLight::Gamma(gamma) = linear_light
+            && !(0.1..=10.0).contains(&gamma)
+        {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.linear_light = linear_light;
+
+        Ok(())
+    }
+
+    pub fn get_color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    /// Sets the color space used for splitting colors, refining the palette,
+    /// and searching for the nearest palette color. Colors are converted to
+    /// Oklab from linear light, so the sRGB transfer function is used when
+    /// linear light is disabled. [`ColorSpace::Rgb`] by default
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        self.color_space = color_space;
+    }
+
+    pub fn get_channel_weights(&self) -> [f32; 4] {
+        self.channel_weights
+    }
+
+    /// Sets weights of the color channels in the color distance metric.
+    /// Weights are applied to red, green, blue, and alpha channels, or to L,
+    /// a, b, and alpha channels when [`ColorSpace::Oklab`] is used.
+    /// All weights are 1.0 by default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if any of the provided weights is
+    /// greater than 100.0 or not greater than 0.0
+    pub fn set_channel_weights(&mut self, weights: [f32; 4]) -> Result<(), Error> {
+        if !weights.iter().all(|&w| w > 0.0 && w <= 100.0) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.channel_weights = weights;
+
+        Ok(())
+    }
+
+    pub fn get_contrast_maps(&self) -> bool {
+        self.contrast_maps
+    }
+
+    /// Sets whether the local contrast of the image should be taken into
+    /// account. Noisy textures get less weight in the histogram than smooth
+    /// areas and edges, and are dithered less during remapping.
+    /// Disabled by default
+    pub fn set_contrast_maps(&mut self, contrast_maps: bool) {
+        self.contrast_maps = contrast_maps;
+    }
+
+    pub fn get_fixed_colors(&self) -> &[Color] {
+        &self.fixed_colors
+    }
+
+    /// Adds a color that should be present in the resultant
+    /// [`Palette`](crate::Palette) unchanged. Other palette colors are
+    /// optimized around the fixed ones. The color should be in the same form
+    /// as palette colors, i.e. premultiplied if
+    /// [`Options::set_premultiplied_palette`] is enabled.
+    ///
+    /// At least one palette entry is left for the image colors, so
+    /// `max_colors` can't be lowered to the number of fixed colors afterwards.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the number of fixed colors would
+    /// reach `max_colors`
+    pub fn add_fixed_color(&mut self, color: Color) -> Result<(), Error> {
+        if self.fixed_colors.contains(&color) {
+            return Ok(());
+        }
+
+        if self.fixed_colors.len() + 1 >= self.max_colors as usize {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.fixed_colors.push(color);
+
+        Ok(())
+    }
+
+    /// Removes all the fixed colors
+    pub fn clear_fixed_colors(&mut self) {
+        self.fixed_colors.clear();
+    }
+
+    pub fn get_quality(&self) -> (u8, u8) {
+        (self.min_quality, self.max_quality)
+    }
+
+    /// Sets the minimum and the maximum quality in 0-100 range. Quality
+    /// grows linearly with the peak signal-to-noise ratio of the pixels:
+    /// quality 0 is 20 dB or lower, every point adds 0.2 dB, and quality 100
+    /// means no error.
+    ///
+    /// If the quality of the result is lower than `min`,
+    /// [`QuantizeResult::try_quantize`](crate::QuantizeResult::try_quantize)
+    /// returns [`Error::QualityTooLow`]. If `max` is less than 100,
+    /// quantization uses fewer colors once this quality is reached.
+    /// The quality range is 0-100 by default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if `max` is greater than 100 or
+    /// `min` is greater than `max`
+    pub fn set_quality(&mut self, min: u8, max: u8) -> Result<(), Error> {
+        if max > 100 || min > max {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.min_quality = min;
+        self.max_quality = max;
+
+        Ok(())
+    }
+
+    pub fn get_target_error(&self) -> f32 {
+        self.target_error
+    }
+
+    /// Sets the target mean squared error of the pixels (see
+    /// [`QuantizeResult::get_error`](crate::QuantizeResult::get_error)).
+    /// Colors stop being split once the estimated error drops to the target,
+    /// so the palette contains as few colors as needed to meet it.
+    /// 0.0 disables the target, which is the default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided error is less than
+    /// 0.0 or is not finite
+    pub fn set_target_error(&mut self, error: f32) -> Result<(), Error> {
+        if !error.is_finite() || error < 0.0 {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.target_error = error;
+
+        Ok(())
+    }
+
+    pub fn get_speed(&self) -> u8 {
+        self.speed
+    }
+
+    /// Sets the speed/quality trade-off in 1-10 range, where 1 is the slowest
+    /// and 10 is the fastest. The speed controls how much the palette is
+    /// refined after colors are split:
+    ///
+    /// * 1 - up to 16 k-means passes until the error stops improving,
+    ///   unused palette colors are moved to the worst represented colors.
+    ///   The slowest one
+    /// * 2 - up to 8 k-means passes and moving unused palette colors
+    /// * 3 - up to 4 k-means passes
+    /// * 4-7 - up to 2 k-means passes
+    /// * 8-10 - a single k-means pass. Gives a slightly worse palette but
+    ///   is noticeably faster on images with many colors
+    ///
+    /// The default speed is 4.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided speed is greater
+    /// than 10 or less than 1
+    pub fn set_speed(&mut self, speed: u8) -> Result<(), Error> {
+        if !(1..=10).contains(&speed) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.speed = speed;
+
+        Ok(())
+    }
+
+    pub fn get_split_strategy(&self) -> SplitStrategy {
+        self.split_strategy
+    }
+
+    /// Sets the strategy of splitting colors into clusters when
+    /// [`Algorithm::MedianCut`] is used. [`SplitStrategy::MedianCut`]
+    /// by default
+    pub fn set_split_strategy(&mut self, strategy: SplitStrategy) {
+        self.split_strategy = strategy;
+    }
+
+    pub fn get_algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Sets the algorithm used to split colors into clusters. Palette
+    /// refinement and remapping are the same for all the algorithms.
+    /// Grayscale images are always split in 2-D, so the algorithm doesn't
+    /// affect them. [`Algorithm::MedianCut`] by default
+    pub fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+    }
+
+    pub fn get_histogram_bits(&self) -> u8 {
+        self.histogram_bits
+    }
+
+    /// Sets the number of bits per channel used to group colors in
+    /// the histogram. Fewer bits make quantization of noisy images faster.
+    /// See [`Histogram::set_bits_per_channel`](crate::Histogram::set_bits_per_channel).
+    /// 8 by default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided number of bits is
+    /// greater than 8 or less than 1
+    pub fn set_histogram_bits(&mut self, bits: u8) -> Result<(), Error> {
+        if !(1..=8).contains(&bits) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.histogram_bits = bits;
+
+        Ok(())
+    }
+
+    pub fn get_max_histogram_entries(&self) -> usize {
+        self.max_histogram_entries
+    }
+
+    /// Sets the maximum number of histogram entries. The number of bits per
+    /// channel is reduced until the histogram fits.
+    /// See [`Histogram::set_max_entries`](crate::Histogram::set_max_entries).
+    /// 0 means no limit, which is the default.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the provided number is less
+    /// than 256 and isn't 0
+    pub fn set_max_histogram_entries(&mut self, max_entries: usize) -> Result<(), Error> {
+        if max_entries != 0 && max_entries < 256 {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        self.max_histogram_entries = max_entries;
+
+        Ok(())
+    }
 }
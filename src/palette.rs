@@ -1,6 +1,9 @@
+use crate::error::Error;
+use crate::image::PixelFormat;
+
 /// RGBA color
 #[repr(C)]
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -36,3 +39,25 @@ impl From<&[[f32; 4]]> for Palette {
         }
     }
 }
+
+impl Palette {
+    /// Writes the palette colors to `buf` as pixels of the provided format.
+    /// This allows getting the palette in the same channel order as the input image.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided buffer is smaller
+    /// than `count * format.bytes_per_pixel()`
+    pub fn write_to(&self, format: PixelFormat, buf: &mut [u8]) -> Result<(), Error> {
+        let count = self.count as usize;
+        let bpp = format.bytes_per_pixel();
+
+        if buf.len() < count * bpp {
+            return Err(Error::BufferTooSmall);
+        }
+
+        for (c, dst) in self.entries[..count].iter().zip(buf.chunks_exact_mut(bpp)) {
+            format.write_pixel([c.r, c.g, c.b, c.a], dst);
+        }
+
+        Ok(())
+    }
+}
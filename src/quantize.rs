@@ -1,49 +1,260 @@
 use crate::cluster::Cluster;
 use crate::colormap::Colormap;
+use crate::colorspace::WorkingSpace;
+use crate::contrast::ContrastMaps;
 use crate::error::Error;
-use crate::histogram::Histogram;
+use crate::gray;
+use crate::histogram::{self, Histogram, HistogramEntry};
 use crate::image::Image;
-use crate::options::Options;
+use crate::neuquant;
+use crate::octree::Octree;
+use crate::options::{Algorithm, Options};
 use crate::palette::Palette;
+use crate::spatial::SpatialQuantizer;
+use crate::wu;
 
-const EMPTY_PIX: [u8; 4] = [0; 4];
+const EMPTY_PIX: [f32; 4] = [0.0; 4];
+
+// Colors are in 0-255 range while quality is mapped from the MSE of 0-1 colors
+const MSE_SCALE: f64 = 255.0 * 255.0;
+// PSNR of quality 0. Images with lower PSNR are hardly recognizable
+const MIN_QUALITY_PSNR: f64 = 20.0;
+// Every quality point adds this much PSNR, so quality 99 is about 40 dB,
+// where quantization artifacts are hard to notice
+const PSNR_PER_QUALITY: f64 = 0.2;
 
 // Result of quantization
 pub struct QuantizeResult {
     error: f32,
+    // Diffused errors greater than this are dampened
+    dithering_threshold: f32,
     dithering_level: f32,
     colormap: Colormap,
+    space: WorkingSpace,
+    contrast_maps: bool,
 }
 
 impl QuantizeResult {
     /// Quantizes the provided [`Image`]
     pub fn quantize(image: &Image, attr: &Options) -> Self {
         let mut hist = Histogram::new();
-        hist.add_image(image);
+        hist.add_image_with_options(image, attr);
 
         Self::quantize_histogram(&hist, attr)
     }
 
+    /// Quantizes the provided [`Image`] and checks the quality of the result.
+    ///
+    /// Returns [`Error::QualityTooLow`] if the quality of the result is lower
+    /// than the minimum quality set in [`Options::set_quality`]
+    pub fn try_quantize(image: &Image, attr: &Options) -> Result<Self, Error> {
+        Self::quantize(image, attr).check_quality(attr)
+    }
+
     /// Quantizes the provided [`Histogram`]
     pub fn quantize_histogram(hist: &Histogram, attr: &Options) -> Self {
+        Self::quantize_colors(hist.entries(), hist.pixels, attr)
+    }
+
+    /// Quantizes the provided [`Octree`]
+    pub fn quantize_octree(octree: &Octree, attr: &Options) -> Self {
+        Self::quantize_colors(octree.leaves().iter(), octree.pixels, attr)
+    }
+
+    /// Quantizes the colors. `pixels` is the number of pixels the colors
+    /// were collected from
+    fn quantize_colors<'a>(
+        colors: impl Iterator<Item = &'a HistogramEntry> + Clone,
+        pixels: usize,
+        attr: &Options,
+    ) -> Self {
         let max_colors = attr.get_max_colors() as usize;
+        let (_, max_quality) = attr.get_quality();
+        let target_error =
+            ((quality_to_mse(max_quality) * MSE_SCALE) as f32).max(attr.get_target_error());
+
+        let fixed = attr.get_fixed_colors();
+        let free_colors = max_colors - fixed.len();
+
+        let mut space = WorkingSpace::new(attr);
+
+        let is_gray = histogram::is_gray(colors.clone())
+            && fixed.iter().all(|c| {
+                let c = space.palette_to_rgba(c);
+                c[0] == c[1] && c[1] == c[2]
+            });
+        space.set_gray_palette(is_gray);
 
-        let colormap = if hist.map.len() <= max_colors {
-            Colormap::from_histogram(hist)
+        let entries = histogram::color_entries(colors, &space);
+
+        let colormap = if entries.len() <= free_colors {
+            Colormap::from_entries(&entries, fixed, &space)
+        } else if is_gray {
+            let clusters = gray::split_gray(&entries, &space, free_colors, target_error);
+
+            Colormap::from_clusters(&clusters, fixed, &space, attr.get_speed())
         } else {
-            let root = Cluster::from_histogram(hist);
-            let clusters = root.split_into(max_colors);
+            let clusters = match attr.get_algorithm() {
+                Algorithm::MedianCut => Cluster::from_entries(&entries).split_into(
+                    free_colors,
+                    target_error,
+                    attr.get_split_strategy(),
+                ),
+                Algorithm::Wu => wu::split_wu(&entries, free_colors, target_error),
+                Algorithm::NeuQuant => {
+                    neuquant::split_neuquant(&entries, free_colors, target_error)
+                }
+            };
 
-            Colormap::from_clusters(&clusters)
+            Colormap::from_clusters(&clusters, fixed, &space, attr.get_speed())
         };
 
         Self {
             error: colormap.error,
+            // Weights of the colors don't affect the threshold, so it
+            // doesn't change when the weights are scaled
+            dithering_threshold: colormap.sq_error_sum / pixels.max(1) as f32,
             colormap,
             dithering_level: 1.0,
+            space,
+            contrast_maps: attr.get_contrast_maps(),
         }
     }
 
+    /// Quantizes the provided [`Histogram`] and checks the quality of
+    /// the result.
+    ///
+    /// Returns [`Error::QualityTooLow`] if the quality of the result is lower
+    /// than the minimum quality set in [`Options::set_quality`]
+    pub fn try_quantize_histogram(hist: &Histogram, attr: &Options) -> Result<Self, Error> {
+        Self::quantize_histogram(hist, attr).check_quality(attr)
+    }
+
+    fn check_quality(self, attr: &Options) -> Result<Self, Error> {
+        let (min_quality, _) = attr.get_quality();
+
+        if self.get_quality() < min_quality {
+            return Err(Error::QualityTooLow);
+        }
+
+        Ok(self)
+    }
+
+    /// Creates a [`QuantizeResult`] from the provided [`Palette`] so images
+    /// can be remapped to it. Palette colors are kept in the provided order.
+    /// Colors are compared according to the provided [`Options`];
+    /// `max_colors` and fixed colors are ignored. Palette colors should be
+    /// premultiplied if [`Options::set_premultiplied_palette`] is enabled.
+    ///
+    /// The quantization error of such a result is 0.0 and the quality is 100
+    /// since they are unknown.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if the palette is empty or its
+    /// count is greater than 256
+    pub fn from_palette(palette: &Palette, attr: &Options) -> Result<Self, Error> {
+        if !(1..=256).contains(&palette.count) {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        let space = WorkingSpace::new(attr);
+        let colormap = Colormap::from_palette(palette, &space);
+
+        Ok(Self {
+            error: 0f32,
+            // Palette doesn't match any image, so we don't dampen the error
+            dithering_threshold: f32::INFINITY,
+            colormap,
+            dithering_level: 1.0,
+            space,
+            contrast_maps: attr.get_contrast_maps(),
+        })
+    }
+
+    /// Quantizes the provided [`Image`] optimizing the palette and the indices
+    /// of the pixels together, and writes the indices to `buf`.
+    ///
+    /// Instead of dithering the image to a ready palette, colors and their
+    /// placement are refined so the image looks closest to the original when
+    /// neighbouring pixels blend. This gives much better results for tiny
+    /// palettes of 2-16 colors, but it's slow for large palettes.
+    /// The number of refinement passes depends on [`Options::set_speed`].
+    /// The dithering level doesn't affect the indices.
+    ///
+    /// The error and the quality of the result are measured per pixel
+    /// without blending the neighbours, so they are usually worse than the
+    /// ones of a regular quantization even though the image looks better.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided buffer is smaller
+    /// than `image.width * image.height`
+    pub fn quantize_spatial(image: &Image, attr: &Options, buf: &mut [u8]) -> Result<Self, Error> {
+        let size = image.width * image.height;
+
+        if buf.len() < size {
+            return Err(Error::BufferTooSmall);
+        }
+
+        let buf = &mut buf[..size];
+
+        // Start with the regular palette and dithered indices
+        let initial = Self::quantize(image, attr);
+        initial.remap_image(image, buf)?;
+
+        let space = &initial.space;
+        let palette = initial.get_palette();
+        let fixed = attr.get_fixed_colors();
+
+        let mut pixels = Vec::with_capacity(size);
+        let mut row = vec![[0f32; 4]; image.width];
+
+        for y in 0..image.height {
+            image.read_row_f32(y, &mut row);
+            pixels.extend(row.iter().map(|pix| initial.pix_to_working(pix)));
+        }
+
+        let entries = &palette.entries[..palette.count as usize];
+        let mut colors: Vec<[f32; 4]> = entries
+            .iter()
+            .map(|c| space.rgba_to_working(space.palette_to_rgba(c)))
+            .collect();
+        let movable: Vec<bool> = entries.iter().map(|c| !fixed.contains(c)).collect();
+
+        // Solved colors are snapped to the palette ones
+        let to_palette = |c: [f32; 4]| space.rgba_to_palette(space.working_to_rgba(c));
+
+        let iterations = 11 - attr.get_speed() as usize;
+
+        let mut quantizer = SpatialQuantizer::new(&pixels, image.width, image.height);
+        quantizer.optimize(&mut colors, &movable, buf, iterations, |c| {
+            space.rgba_to_working(space.palette_to_rgba(&to_palette(c)))
+        });
+        quantizer.update_indices(&colors, buf);
+
+        let mut palette = Palette {
+            count: palette.count,
+            entries: palette.entries,
+        };
+        for (pe, c) in palette.entries.iter_mut().zip(&colors) {
+            *pe = to_palette(*c);
+        }
+
+        let mut total_sq_err = 0f64;
+        for (pix, &ind) in pixels.iter().zip(buf.iter()) {
+            let c = &colors[ind as usize];
+            let err: f32 = pix.iter().zip(c).map(|(p, c)| (p - c) * (p - c)).sum();
+            total_sq_err += err as f64;
+        }
+        let mse = (total_sq_err / size.max(1) as f64) as f32;
+
+        Ok(Self {
+            error: mse,
+            dithering_threshold: initial.dithering_threshold,
+            colormap: Colormap::from_palette(&palette, space),
+            dithering_level: 1.0,
+            space: initial.space,
+            contrast_maps: attr.get_contrast_maps(),
+        })
+    }
+
     /// Sets the dithering level.
     ///
     /// Returns [`Error::ValueOutOfRange`] if the provided value is greater
@@ -59,11 +270,30 @@ impl QuantizeResult {
     }
 
     /// Returns quantization error. The lesser the error the better the image
-    /// was quantized
+    /// was quantized. It's the mean squared error of the pixels in the color
+    /// space used for quantization, where colors are in 0-255 range.
+    /// Colors are weighted, so the error doesn't depend on the scale of
+    /// the weights
     pub fn get_error(&self) -> f32 {
         self.error
     }
 
+    /// Returns the quality of the result in 0-100 range (see
+    /// [`Options::set_quality`]). The quality is based on the mean squared
+    /// error of the pixels in the color space used for quantization, so
+    /// it's approximate when
+    /// linear light, Oklab, or channel weights are used
+    pub fn get_quality(&self) -> u8 {
+        mse_to_quality(self.error as f64 / MSE_SCALE)
+    }
+
+    /// Returns the number of colors in the resultant [`Palette`]. It can be
+    /// less than `max_colors` if the image has fewer colors or the target
+    /// error or the maximum quality was reached with fewer colors
+    pub fn get_colors_count(&self) -> u32 {
+        self.colormap.get_palette().count
+    }
+
     /// Returns the [`Palette`] generated after quantization
     pub fn get_palette(&self) -> &Palette {
         self.colormap.get_palette()
@@ -74,55 +304,89 @@ impl QuantizeResult {
     /// Returns [`Error::BufferTooSmall`] if the provided buffer is smaller
     /// than `image.width * image.height`
     pub fn remap_image(&self, image: &Image, buf: &mut [u8]) -> Result<(), Error> {
-        if buf.len() < image.width * image.height {
-            return Err(Error::BufferTooSmall);
+        self.remap_image_with_stride(image, buf, image.width)
+    }
+
+    /// Remaps the proxided [`Image`] to a slize of bytes where rows are
+    /// `stride` bytes apart.
+    ///
+    /// Returns [`Error::ValueOutOfRange`] if `stride` is less than
+    /// `image.width`.
+    ///
+    /// Returns [`Error::BufferTooSmall`] if the provided buffer is smaller
+    /// than `stride * (image.height - 1) + image.width`
+    pub fn remap_image_with_stride(
+        &self,
+        image: &Image,
+        buf: &mut [u8],
+        stride: usize,
+    ) -> Result<(), Error> {
+        if stride < image.width {
+            return Err(Error::ValueOutOfRange);
+        }
+
+        if image.height > 0 {
+            let size = stride
+                .checked_mul(image.height - 1)
+                .and_then(|s| s.checked_add(image.width))
+                .ok_or(Error::BufferTooSmall)?;
+
+            if buf.len() < size {
+                return Err(Error::BufferTooSmall);
+            }
         }
 
         if self.dithering_level > 0.0 {
-            self.remap_image_dither(image, buf);
+            self.remap_image_dither(image, buf, stride);
         } else {
-            self.remap_image_no_dither(image, buf);
+            self.remap_image_no_dither(image, buf, stride);
         }
 
         Ok(())
     }
 
-    fn remap_image_no_dither(&self, image: &Image, buf: &mut [u8]) {
-        #[allow(clippy::needless_range_loop)]
-        for point in 0..image.width * image.height {
-            let data_point = point * 4;
+    fn remap_image_no_dither(&self, image: &Image, buf: &mut [u8], stride: usize) {
+        let mut row = vec![[0f32; 4]; image.width];
 
-            let pix = pix_or_empty(&image.data[data_point..data_point + 4]);
-            let r = pix[0] as f32;
-            let g = pix[1] as f32;
-            let b = pix[2] as f32;
-            let a = pix[3] as f32;
+        for y in 0..image.height {
+            image.read_row_f32(y, &mut row);
 
-            let (ind, _, _) = self.colormap.nearest_ind(&[r, g, b, a]);
+            for (x, pix) in row.iter().enumerate() {
+                let pix = self.pix_to_working(pix);
 
-            buf[point] = ind;
+                let (ind, _, _) = self.colormap.nearest_ind(&pix);
+
+                buf[stride * y + x] = ind;
+            }
         }
     }
 
-    fn remap_image_dither(&self, image: &Image, buf: &mut [u8]) {
+    fn remap_image_dither(&self, image: &Image, buf: &mut [u8], stride: usize) {
         let error_size = image.width + 2;
         let mut error_curr = vec![[0f32; 4]; error_size];
         let mut error_next = vec![[0f32; 4]; error_size];
 
         let dithering_coeff = self.dithering_level * 15.0 / 16.0 / 16.0;
-        let err_threshold = self.error;
-        // println!("Err threshold {}", self.error);
+        let err_threshold = self.dithering_threshold;
+
+        let dither_map = if self.contrast_maps {
+            ContrastMaps::new(image).map(|m| m.dither)
+        } else {
+            None
+        };
 
+        let mut row = vec![[0f32; 4]; image.width];
         let mut x_reverse = true;
 
         for y in 0..image.height {
             x_reverse = !x_reverse;
 
+            image.read_row_f32(y, &mut row);
+
             for xx in 0..image.width {
                 let x = if x_reverse { image.width - 1 - xx } else { xx };
 
-                let point = image.width * y + x;
-                let data_point = point * 4;
+                let point = stride * y + x;
 
                 let err_ind = x + 1;
                 let err_inds = if x_reverse {
@@ -145,12 +409,12 @@ impl QuantizeResult {
                     err_pix[3] *= 0.8;
                 }
 
-                let pix = pix_or_empty(&image.data[data_point..data_point + 4]);
+                let pix = self.pix_to_working(&row[x]);
                 let dith_pix = [
-                    pix[0] as f32 + err_pix[0],
-                    pix[1] as f32 + err_pix[1],
-                    pix[2] as f32 + err_pix[2],
-                    pix[3] as f32 + err_pix[3],
+                    pix[0] + err_pix[0],
+                    pix[1] + err_pix[1],
+                    pix[2] + err_pix[2],
+                    pix[3] + err_pix[3],
                 ];
 
                 let (ind, pal_pix, _) = self.colormap.nearest_ind(&dith_pix);
@@ -170,6 +434,11 @@ impl QuantizeResult {
                     err_a *= 0.75;
                 }
 
+                let dithering_coeff = match &dither_map {
+                    Some(map) => dithering_coeff * map[y * image.width + x] as f32 / 255.0,
+                    None => dithering_coeff,
+                };
+
                 err_r *= dithering_coeff;
                 err_g *= dithering_coeff;
                 err_b *= dithering_coeff;
@@ -204,12 +473,534 @@ impl QuantizeResult {
             error_next.fill_with(|| [0f32; 4]);
         }
     }
+
+    #[inline(always)]
+    fn pix_to_working(&self, pix: &[f32; 4]) -> [f32; 4] {
+        self.space.rgba_to_working(*pix_or_empty(pix))
+    }
+}
+
+/// Converts quality to the mean squared error of 0-1 colors. Quality grows
+/// linearly with PSNR, which is `-10 * log10(mse)` for 0-1 colors.
+/// Quality 100 means no error
+fn quality_to_mse(quality: u8) -> f64 {
+    if quality == 0 {
+        return f64::MAX;
+    }
+
+    if quality >= 100 {
+        return 0.0;
+    }
+
+    let psnr = MIN_QUALITY_PSNR + quality as f64 * PSNR_PER_QUALITY;
+
+    10f64.powf(-psnr / 10.0)
+}
+
+fn mse_to_quality(mse: f64) -> u8 {
+    (1..=100)
+        .rev()
+        .find(|&q| mse <= quality_to_mse(q) + 0.000001)
+        .unwrap_or(0)
 }
 
 #[inline(always)]
-fn pix_or_empty(pix: &[u8]) -> &[u8] {
-    if pix[3] == 0 {
+fn pix_or_empty(pix: &[f32; 4]) -> &[f32; 4] {
+    if pix[3] < 0.5 {
         return &EMPTY_PIX;
     }
     pix
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::PixelFormat;
+    use crate::palette::Color;
+    use crate::test_images;
+
+    #[test]
+    fn test_quantize_gray_palette_is_neutral() {
+        let (width, height) = (64, 64);
+        let mut data = vec![0u8; width * height * 2];
+
+        for (i, pix) in data.chunks_exact_mut(2).enumerate() {
+            pix[0] = (i * 7 % 256) as u8;
+            pix[1] = (i / width * 4) as u8;
+        }
+
+        let image = Image::with_format(&data, width, height, PixelFormat::GrayAlpha8).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_max_colors(16).unwrap();
+        opts.set_premultiplied_alpha(true);
+
+        let result = QuantizeResult::quantize(&image, &opts);
+        let palette = result.get_palette();
+
+        assert_eq!(palette.count, 16);
+        for c in palette.entries[..16].iter() {
+            assert!(c.r == c.g && c.g == c.b);
+        }
+    }
+
+    #[test]
+    fn test_quantize_channel_weights() {
+        let (width, height) = (64, 64);
+        let mut data = test_images::gradient(width, height);
+
+        // Slightly different alpha levels in stripes
+        for (i, pix) in data.chunks_exact_mut(4).enumerate() {
+            pix[3] = 255 - (i % width / 16 * 12) as u8;
+        }
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let alpha_levels = |weights| {
+            let mut opts = Options::default();
+            opts.set_max_colors(8).unwrap();
+            opts.set_channel_weights(weights).unwrap();
+
+            let result = QuantizeResult::quantize(&image, &opts);
+            let palette = result.get_palette();
+
+            let mut levels: Vec<u8> = palette.entries[..palette.count as usize]
+                .iter()
+                .map(|c| c.a)
+                .collect();
+            levels.sort();
+            levels.dedup();
+            levels
+        };
+
+        assert!(alpha_levels([1.0; 4]).len() < 4);
+        assert_eq!(alpha_levels([1.0, 1.0, 1.0, 100.0]), [219, 231, 243, 255]);
+    }
+
+    #[test]
+    fn test_quantize_fixed_colors() {
+        let (width, height) = (64, 64);
+        let data = test_images::gradient(width, height);
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let fixed = [
+            Color {
+                r: 255,
+                g: 0,
+                b: 255,
+                a: 255,
+            },
+            Color {
+                r: 1,
+                g: 2,
+                b: 3,
+                a: 4,
+            },
+        ];
+
+        let mut opts = Options::default();
+        opts.set_max_colors(16).unwrap();
+        for c in fixed {
+            opts.add_fixed_color(c).unwrap();
+        }
+
+        assert!(matches!(
+            opts.set_max_colors(2),
+            Err(Error::ValueOutOfRange)
+        ));
+        assert_eq!(opts.get_max_colors(), 16);
+
+        let result = QuantizeResult::quantize(&image, &opts);
+        let palette = result.get_palette();
+
+        assert_eq!(palette.count, 16);
+        for c in fixed {
+            assert!(palette.entries[..16].contains(&c));
+        }
+    }
+
+    #[test]
+    fn test_quantize_result_from_palette() {
+        let mut palette = Palette {
+            count: 0,
+            entries: [Color::default(); 256],
+        };
+
+        assert!(QuantizeResult::from_palette(&palette, &Options::default()).is_err());
+
+        palette.count = 2;
+        palette.entries[0] = Color {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        };
+        palette.entries[1] = Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+
+        let data = [10, 10, 10, 255, 250, 250, 250, 255];
+        let image = Image::new(&data, 2, 1).unwrap();
+        let mut buf = [0u8; 2];
+
+        let mut result = QuantizeResult::from_palette(&palette, &Options::default()).unwrap();
+        result.set_dithering_level(0.0).unwrap();
+        result.remap_image(&image, &mut buf).unwrap();
+
+        assert_eq!(buf, [1, 0]);
+    }
+
+    #[test]
+    fn test_quantize_quality() {
+        let (width, height) = (64, 64);
+        let mut data = vec![0u8; width * height * 4];
+
+        for (i, pix) in data.chunks_exact_mut(4).enumerate() {
+            pix[0] = (i % width * 4) as u8;
+            pix[1] = (i / width * 4) as u8;
+            pix[2] = ((i % width + i / width) * 2) as u8;
+            pix[3] = 255;
+        }
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_quality(0, 30).unwrap();
+
+        let result = QuantizeResult::try_quantize(&image, &opts).unwrap();
+
+        assert!(result.get_palette().count < 256);
+        assert!(result.get_quality() >= 30);
+
+        opts.set_max_colors(2).unwrap();
+        opts.set_quality(90, 100).unwrap();
+
+        assert!(matches!(
+            QuantizeResult::try_quantize(&image, &opts),
+            Err(Error::QualityTooLow)
+        ));
+    }
+
+    #[test]
+    fn test_quantize_quality_mapping() {
+        // Quality 50 is 30 dB
+        assert!((quality_to_mse(50) - 0.001).abs() < 1e-12);
+        assert_eq!(quality_to_mse(100), 0.0);
+
+        for q in 1..=100 {
+            assert_eq!(mse_to_quality(quality_to_mse(q)), q);
+        }
+        assert_eq!(mse_to_quality(0.1), 0);
+    }
+
+    #[test]
+    fn test_quantize_target_error() {
+        let (width, height) = (64, 64);
+        let data = test_images::gradient(width, height);
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_target_error(100.0).unwrap();
+
+        for algorithm in [Algorithm::MedianCut, Algorithm::Wu, Algorithm::NeuQuant] {
+            opts.set_algorithm(algorithm);
+            let result = QuantizeResult::quantize(&image, &opts);
+
+            assert!(result.get_colors_count() < 256, "{:?}", algorithm);
+            assert!(result.get_error() <= 100.0, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_quantize_algorithms() {
+        let (width, height) = (64, 64);
+        let mut data = test_images::gradient(width, height);
+
+        for (i, pix) in data.chunks_exact_mut(4).enumerate() {
+            pix[3] = if i % 3 == 0 { 128 } else { 255 };
+        }
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_max_colors(32).unwrap();
+
+        let median_cut = QuantizeResult::quantize(&image, &opts);
+
+        for algorithm in [Algorithm::Wu, Algorithm::NeuQuant] {
+            opts.set_algorithm(algorithm);
+            let result = QuantizeResult::quantize(&image, &opts);
+
+            // NeuQuant can leave a few neurons unused
+            assert!(result.get_colors_count() > 28, "{:?}", algorithm);
+            assert!(
+                result.get_error() < median_cut.get_error() * 1.5,
+                "{:?}",
+                algorithm
+            );
+        }
+    }
+
+    // Sum of squared differences between the original and the remapped
+    // images, both blurred with a 3x3 Gaussian filter
+    fn filtered_error(
+        data: &[u8],
+        width: usize,
+        height: usize,
+        palette: &Palette,
+        buf: &[u8],
+    ) -> f64 {
+        const FILTER: [f64; 3] = [0.25, 0.5, 0.25];
+
+        let diff = |x: usize, y: usize, ch: usize| {
+            let p = y * width + x;
+            let c = &palette.entries[buf[p] as usize];
+            [c.r, c.g, c.b, c.a][ch] as f64 - data[p * 4 + ch] as f64
+        };
+
+        let mut total = 0.0;
+        for y in 0..height {
+            for x in 0..width {
+                for ch in 0..4 {
+                    let mut d = 0.0;
+                    for (dy, wy) in FILTER.iter().enumerate() {
+                        for (dx, wx) in FILTER.iter().enumerate() {
+                            let (qx, qy) = ((x + dx).wrapping_sub(1), (y + dy).wrapping_sub(1));
+                            if qx < width && qy < height {
+                                d += diff(qx, qy, ch) * wx * wy;
+                            }
+                        }
+                    }
+                    total += d * d;
+                }
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn test_quantize_spatial() {
+        let (width, height) = (32, 32);
+        let data = test_images::gradient(width, height);
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_max_colors(4).unwrap();
+        // A single optimization round
+        opts.set_speed(10).unwrap();
+
+        let mut buf = vec![0u8; width * height - 1];
+        assert!(QuantizeResult::quantize_spatial(&image, &opts, &mut buf).is_err());
+
+        let mut buf = vec![0u8; width * height];
+        let result = QuantizeResult::quantize_spatial(&image, &opts, &mut buf).unwrap();
+        let count = result.get_colors_count();
+
+        assert_eq!(count, 4);
+        assert!(buf.iter().all(|&i| (i as u32) < count));
+
+        let plain = QuantizeResult::quantize(&image, &opts);
+        let mut plain_buf = vec![0u8; width * height];
+        plain.remap_image(&image, &mut plain_buf).unwrap();
+
+        let spatial_err = filtered_error(&data, width, height, result.get_palette(), &buf);
+        let plain_err = filtered_error(&data, width, height, plain.get_palette(), &plain_buf);
+
+        assert!(spatial_err < plain_err);
+    }
+
+    #[test]
+    fn test_quantize_weight_scale() {
+        let (width, height) = (128, 128);
+        let mut data = vec![0u8; width * height * 4];
+
+        // Repeated colors so entries get different weights
+        for (i, pix) in data.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % width / 3, i / width / 2);
+            pix[0] = (x * 6) as u8;
+            pix[1] = (y * 4) as u8;
+            pix[2] = ((x + y) * 2) as u8;
+            pix[3] = 255;
+        }
+
+        let image = Image::new(&data, width, height).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_max_colors(32).unwrap();
+
+        let mut results = Vec::new();
+
+        for (weight, normalize) in [(1.0, false), (1.0, true), (1000.0, false)] {
+            let mut hist = Histogram::new();
+            hist.add_image_weighted(&image, weight, normalize).unwrap();
+
+            let result = QuantizeResult::quantize_histogram(&hist, &opts);
+            let mut buf = vec![0u8; width * height];
+            result.remap_image(&image, &mut buf).unwrap();
+
+            results.push((result.get_error(), buf));
+        }
+
+        let (error, buf) = &results[0];
+        for (e, b) in &results[1..] {
+            assert!((e - error).abs() <= error * 0.001, "{} {}", e, error);
+            assert!(b == buf);
+        }
+    }
+
+    #[test]
+    fn test_quantize_uniform_importance_map() {
+        let (width, height) = (64, 64);
+        let data = test_images::gradient(width, height);
+
+        let mut opts = Options::default();
+        opts.set_max_colors(16).unwrap();
+
+        let image = Image::new(&data, width, height).unwrap();
+        let result = QuantizeResult::quantize(&image, &opts);
+        let mut buf = vec![0u8; width * height];
+        result.remap_image(&image, &mut buf).unwrap();
+
+        let map = vec![64u8; width * height];
+        let mut weighted_image = Image::new(&data, width, height).unwrap();
+        weighted_image.set_importance_map(&map).unwrap();
+
+        let weighted = QuantizeResult::quantize(&weighted_image, &opts);
+        let mut weighted_buf = vec![0u8; width * height];
+        weighted
+            .remap_image(&weighted_image, &mut weighted_buf)
+            .unwrap();
+
+        let error = result.get_error();
+        assert!((weighted.get_error() - error).abs() <= error * 0.001);
+        // The palette order can differ since scaled weights break ties
+        // differently, so remapped colors are compared
+        let colors = |r: &QuantizeResult, buf: &[u8]| -> Vec<Color> {
+            buf.iter()
+                .map(|&i| r.get_palette().entries[i as usize])
+                .collect()
+        };
+        assert_eq!(colors(&weighted, &weighted_buf), colors(&result, &buf));
+    }
+
+    #[test]
+    fn test_quantize_histogram_colors_weight_scale() {
+        let colors: Vec<(Color, f64)> = (0..1024)
+            .map(|i| {
+                let c = Color {
+                    r: (i % 32 * 8) as u8,
+                    g: (i / 32 * 8) as u8,
+                    b: (i % 7 * 30) as u8,
+                    a: 255,
+                };
+                (c, (i % 5 + 1) as f64)
+            })
+            .collect();
+
+        let data: Vec<u8> = colors
+            .iter()
+            .flat_map(|(c, _)| [c.r, c.g, c.b, c.a])
+            .collect();
+        let image = Image::new(&data, 32, 32).unwrap();
+
+        let mut opts = Options::default();
+        opts.set_max_colors(16).unwrap();
+
+        let mut results = Vec::new();
+
+        for scale in [1.0, 7.5] {
+            let scaled: Vec<(Color, f64)> = colors.iter().map(|&(c, w)| (c, w * scale)).collect();
+
+            let mut hist = Histogram::new();
+            hist.add_colors(&scaled).unwrap();
+
+            let result = QuantizeResult::quantize_histogram(&hist, &opts);
+            let mut buf = vec![0u8; 32 * 32];
+            result.remap_image(&image, &mut buf).unwrap();
+
+            let remapped: Vec<Color> = buf
+                .iter()
+                .map(|&i| result.get_palette().entries[i as usize])
+                .collect();
+
+            results.push((result.get_error(), remapped));
+        }
+
+        let (error, remapped) = &results[0];
+        assert!((results[1].0 - error).abs() <= error * 0.001);
+        assert_eq!(&results[1].1, remapped);
+    }
+
+    #[test]
+    fn test_quantize_histogram_extreme_weights() {
+        let colors: Vec<Color> = (0..64)
+            .map(|i| Color {
+                r: (i % 8 * 32) as u8,
+                g: (i / 8 * 32) as u8,
+                b: 128,
+                a: 255,
+            })
+            .collect();
+
+        let mut opts = Options::default();
+        opts.set_max_colors(16).unwrap();
+
+        for weight in [1e300, 1e-300] {
+            let mut hist = Histogram::new();
+            for (i, &c) in colors.iter().enumerate() {
+                hist.add_color(c, weight * (i % 3 + 1) as f64).unwrap();
+            }
+
+            let result = QuantizeResult::quantize_histogram(&hist, &opts);
+            let palette = result.get_palette();
+
+            assert!(result.get_error().is_finite(), "{}", weight);
+            assert_eq!(palette.count, 16);
+            assert!(palette.entries[..16].iter().all(|c| c.a == 255));
+        }
+    }
+
+    #[test]
+    fn test_quantize_remap_with_stride() {
+        let (width, height, stride) = (5, 3, 8);
+        let data: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i * 16) as u8, 0, 0, 255])
+            .collect();
+
+        let image = Image::new(&data, width, height).unwrap();
+        let result = QuantizeResult::quantize(&image, &Options::default());
+
+        let mut buf = vec![0u8; width * height];
+        result.remap_image(&image, &mut buf).unwrap();
+
+        let mut strided = vec![0xaau8; stride * (height - 1) + width];
+        assert!(matches!(
+            result.remap_image_with_stride(&image, &mut strided[1..], stride),
+            Err(Error::BufferTooSmall)
+        ));
+        assert!(matches!(
+            result.remap_image_with_stride(&image, &mut strided, usize::MAX),
+            Err(Error::BufferTooSmall)
+        ));
+
+        result
+            .remap_image_with_stride(&image, &mut strided, stride)
+            .unwrap();
+
+        for y in 0..height {
+            let row = &strided[y * stride..];
+            assert_eq!(row[..width], buf[y * width..(y + 1) * width]);
+
+            // Padding bytes are untouched
+            if y + 1 < height {
+                assert!(row[width..stride].iter().all(|&b| b == 0xaa));
+            }
+        }
+    }
+}
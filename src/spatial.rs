@@ -0,0 +1,281 @@
+// 3x3 Gaussian filter that models how the eye blurs neighbouring pixels.
+// Dithered pixels are perceived as the filtered colors
+const FILTER: [f32; 9] = [
+    1.0 / 16.0,
+    2.0 / 16.0,
+    1.0 / 16.0,
+    2.0 / 16.0,
+    4.0 / 16.0,
+    2.0 / 16.0,
+    1.0 / 16.0,
+    2.0 / 16.0,
+    1.0 / 16.0,
+];
+
+// The filter applied twice. Used to solve the palette
+const FILTER_SQ: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+/// Jointly optimizes the palette and the indices of the pixels so
+/// the filtered remapped image is as close as possible to the filtered
+/// original image
+pub(crate) struct SpatialQuantizer<'a> {
+    pixels: &'a [[f32; 4]],
+    width: usize,
+    height: usize,
+    // Filtered difference between the remapped and the original image
+    residual: Vec<[f32; 4]>,
+}
+
+impl<'a> SpatialQuantizer<'a> {
+    pub(crate) fn new(pixels: &'a [[f32; 4]], width: usize, height: usize) -> Self {
+        Self {
+            pixels,
+            width,
+            height,
+            residual: vec![[0.0; 4]; pixels.len()],
+        }
+    }
+
+    /// Runs `iterations` rounds of picking the best indices and solving
+    /// the palette. Only colors marked as `movable` are changed, and solved
+    /// colors are passed through `clamp` so they stay representable
+    pub(crate) fn optimize(
+        &mut self,
+        colors: &mut [[f32; 4]],
+        movable: &[bool],
+        indices: &mut [u8],
+        iterations: usize,
+        clamp: impl Fn([f32; 4]) -> [f32; 4],
+    ) {
+        for _ in 0..iterations {
+            self.update_indices(colors, indices);
+            self.solve_palette(colors, movable, indices, &clamp);
+        }
+    }
+
+    /// Picks the index of every pixel that reduces the filtered error the
+    /// most, given the indices of the other pixels
+    pub(crate) fn update_indices(&mut self, colors: &[[f32; 4]], indices: &mut [u8]) {
+        self.calc_residual(colors, indices);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let p = y * self.width + x;
+                let current = colors[indices[p] as usize];
+
+                // Gradient of the error by the pixel color and the sum of
+                // squared filter weights that affect the pixel
+                let mut grad = [0f32; 4];
+                let mut weight_sq = 0f32;
+
+                self.for_neighbours(x, y, |q, w| {
+                    for (g, r) in grad.iter_mut().zip(self.residual[q]) {
+                        *g += r * w;
+                    }
+                    weight_sq += w * w;
+                });
+
+                let mut best = indices[p];
+                let mut best_delta = 0f32;
+
+                for (i, c) in colors.iter().enumerate() {
+                    let mut delta = 0f32;
+                    for ch in 0..4 {
+                        let d = c[ch] - current[ch];
+                        delta += 2.0 * d * grad[ch] + d * d * weight_sq;
+                    }
+
+                    if delta < best_delta {
+                        best_delta = delta;
+                        best = i as u8;
+                    }
+                }
+
+                if best != indices[p] {
+                    let new = colors[best as usize];
+                    let d = [0, 1, 2, 3].map(|ch| new[ch] - current[ch]);
+
+                    let (residual, width, height) = (&mut self.residual, self.width, self.height);
+                    for_neighbours(width, height, x, y, |q, w| {
+                        for (r, dc) in residual[q].iter_mut().zip(d) {
+                            *r += dc * w;
+                        }
+                    });
+
+                    indices[p] = best;
+                }
+            }
+        }
+    }
+
+    /// Finds palette colors that minimize the filtered error for
+    /// the current indices by solving the least squares problem
+    fn solve_palette(
+        &self,
+        colors: &mut [[f32; 4]],
+        movable: &[bool],
+        indices: &[u8],
+        clamp: impl Fn([f32; 4]) -> [f32; 4],
+    ) {
+        let k = colors.len();
+        let mut a = vec![0f64; k * k];
+        let mut b = vec![[0f64; 4]; k];
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let p = y * self.width + x;
+                let ip = indices[p] as usize;
+
+                let mut filtered = [0f64; 4];
+
+                for (dy, wy) in FILTER_SQ.iter().enumerate() {
+                    let Some(qy) = (y + dy).checked_sub(2).filter(|&qy| qy < self.height) else {
+                        continue;
+                    };
+
+                    for (dx, wx) in FILTER_SQ.iter().enumerate() {
+                        let Some(qx) = (x + dx).checked_sub(2).filter(|&qx| qx < self.width) else {
+                            continue;
+                        };
+
+                        let q = qy * self.width + qx;
+                        let w = (wy * wx) as f64;
+
+                        a[ip * k + indices[q] as usize] += w;
+                        for (f, c) in filtered.iter_mut().zip(self.pixels[q]) {
+                            *f += c as f64 * w;
+                        }
+                    }
+                }
+
+                for (bc, f) in b[ip].iter_mut().zip(filtered) {
+                    *bc += f;
+                }
+            }
+        }
+
+        // Colors that can't move or aren't used are known, so we move them
+        // to the right side
+        for i in 0..k {
+            if movable[i] && a[i * k + i] > 0.0 {
+                continue;
+            }
+
+            for j in 0..k {
+                if j != i {
+                    for (bc, c) in b[j].iter_mut().zip(colors[i]) {
+                        *bc -= a[j * k + i] * c as f64;
+                    }
+                    a[j * k + i] = 0.0;
+                    a[i * k + j] = 0.0;
+                }
+            }
+
+            a[i * k + i] = 1.0;
+            b[i] = colors[i].map(|c| c as f64);
+        }
+
+        if let Some(solved) = solve(&mut a, &mut b, k) {
+            for ((c, s), &m) in colors.iter_mut().zip(solved).zip(movable) {
+                if m {
+                    *c = clamp(s.map(|v| v as f32));
+                }
+            }
+        }
+    }
+
+    fn calc_residual(&mut self, colors: &[[f32; 4]], indices: &[u8]) {
+        self.residual.fill([0.0; 4]);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let p = y * self.width + x;
+                let c = colors[indices[p] as usize];
+                let d = [0, 1, 2, 3].map(|ch| c[ch] - self.pixels[p][ch]);
+
+                let (residual, width, height) = (&mut self.residual, self.width, self.height);
+                for_neighbours(width, height, x, y, |q, w| {
+                    for (r, dc) in residual[q].iter_mut().zip(d) {
+                        *r += dc * w;
+                    }
+                });
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn for_neighbours(&self, x: usize, y: usize, f: impl FnMut(usize, f32)) {
+        for_neighbours(self.width, self.height, x, y, f);
+    }
+}
+
+/// Calls `f` with the index and the filter weight of every pixel within
+/// the filter around (x, y)
+#[inline(always)]
+fn for_neighbours(width: usize, height: usize, x: usize, y: usize, mut f: impl FnMut(usize, f32)) {
+    for dy in 0..3 {
+        let Some(qy) = (y + dy).checked_sub(1).filter(|&qy| qy < height) else {
+            continue;
+        };
+
+        for dx in 0..3 {
+            let Some(qx) = (x + dx).checked_sub(1).filter(|&qx| qx < width) else {
+                continue;
+            };
+
+            f(qy * width + qx, FILTER[dy * 3 + dx]);
+        }
+    }
+}
+
+/// Solves `a * x = b` for 4 right sides using Gaussian elimination with
+/// partial pivoting. Returns `None` if the matrix is singular
+fn solve(a: &mut [f64], b: &mut [[f64; 4]], k: usize) -> Option<Vec<[f64; 4]>> {
+    for col in 0..k {
+        let pivot =
+            (col..k).max_by(|&i, &j| a[i * k + col].abs().total_cmp(&a[j * k + col].abs()))?;
+
+        if a[pivot * k + col].abs() < 1e-12 {
+            return None;
+        }
+
+        if pivot != col {
+            for j in 0..k {
+                a.swap(pivot * k + j, col * k + j);
+            }
+            b.swap(pivot, col);
+        }
+
+        for row in col + 1..k {
+            let factor = a[row * k + col] / a[col * k + col];
+            if factor == 0.0 {
+                continue;
+            }
+
+            for j in col..k {
+                a[row * k + j] -= factor * a[col * k + j];
+            }
+
+            let bc = b[col];
+            for (br, bc) in b[row].iter_mut().zip(bc) {
+                *br -= factor * bc;
+            }
+        }
+    }
+
+    let mut x = vec![[0f64; 4]; k];
+
+    for row in (0..k).rev() {
+        let mut v = b[row];
+
+        for j in row + 1..k {
+            for (vc, xc) in v.iter_mut().zip(x[j]) {
+                *vc -= a[row * k + j] * xc;
+            }
+        }
+
+        x[row] = v.map(|vc| vc / a[row * k + row]);
+    }
+
+    Some(x)
+}
@@ -0,0 +1,27 @@
+/// Returns RGBA data of a smooth red-green gradient on a constant blue
+pub(crate) fn gradient(width: usize, height: usize) -> Vec<u8> {
+    let mut data = vec![0u8; width * height * 4];
+
+    for (i, pix) in data.chunks_exact_mut(4).enumerate() {
+        pix[0] = (i % width * 4) as u8;
+        pix[1] = (i / width * 4) as u8;
+        pix[2] = 128;
+        pix[3] = 255;
+    }
+
+    data
+}
+
+/// Returns RGBA data of a pattern with a lot of unique colors
+pub(crate) fn pattern(width: usize, height: usize) -> Vec<u8> {
+    let mut data = vec![0u8; width * height * 4];
+
+    for (i, pix) in data.chunks_exact_mut(4).enumerate() {
+        pix[0] = (i % width) as u8;
+        pix[1] = (i / width * 4) as u8;
+        pix[2] = (i * 7 % 256) as u8;
+        pix[3] = 255;
+    }
+
+    data
+}
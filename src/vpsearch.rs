@@ -97,6 +97,7 @@ impl SearchNode {
         Some(Box::new(node))
     }
 
+    #[allow(clippy::collapsible_if)]
     fn visit<'a>(&'a self, pin: &[f32; 4], nearest: &mut SearchVisitor<'a>) {
         let distance_sq = dist(&self.ind.data, pin);
 
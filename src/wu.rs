@@ -0,0 +1,69 @@
+use crate::cluster::Cluster;
+use crate::colorspace::ColorEntry;
+use crate::moments;
+
+// Number of bins per channel when up to 3 channels vary
+const BINS_3D: usize = 32;
+// Number of bins per channel when all 4 channels vary. Keeps the table small
+const BINS_4D: usize = 16;
+
+/// Maps colors of the working space to grid bins
+struct Binning {
+    min: [f32; 4],
+    scale: [f32; 4],
+    bins: [usize; 4],
+}
+
+impl Binning {
+    fn new(entries: &[ColorEntry]) -> Self {
+        let mut min = [f32::MAX; 4];
+        let mut max = [f32::MIN; 4];
+
+        for e in entries {
+            for c in 0..4 {
+                min[c] = min[c].min(e.color[c]);
+                max[c] = max[c].max(e.color[c]);
+            }
+        }
+
+        let active = (0..4).filter(|&c| max[c] > min[c]).count();
+        let bins_count = if active > 3 { BINS_4D } else { BINS_3D };
+
+        let mut bins = [1usize; 4];
+        let mut scale = [0f32; 4];
+
+        for c in 0..4 {
+            if max[c] > min[c] {
+                bins[c] = bins_count;
+                scale[c] = bins_count as f32 / (max[c] - min[c]);
+            }
+        }
+
+        Self { min, scale, bins }
+    }
+
+    #[inline(always)]
+    fn bin(&self, color: &[f32; 4]) -> [usize; 4] {
+        let mut bin = [0usize; 4];
+
+        for c in 0..4 {
+            let v = ((color[c] - self.min[c]) * self.scale[c]) as usize;
+            bin[c] = v.min(self.bins[c] - 1);
+        }
+
+        bin
+    }
+}
+
+/// Splits colors into clusters using Xiaolin Wu's algorithm. Colors are
+/// binned into a 4-D grid of cumulative moments. See [`moments::split_boxes`]
+pub(crate) fn split_wu(
+    entries: &[ColorEntry],
+    max_colors: usize,
+    target_error: f32,
+) -> Vec<Cluster<'_>> {
+    let binning = Binning::new(entries);
+    let bins: Vec<[usize; 4]> = entries.iter().map(|e| binning.bin(&e.color)).collect();
+
+    moments::split_boxes(entries, &bins, binning.bins, max_colors, target_error)
+}